@@ -0,0 +1,15 @@
+//! Layout of the header occupying the first words of every ROM.
+
+pub const SIZE: usize = 0x40;
+pub const MAGIC: u32 = 0x504c54;
+
+pub const FEATURES: usize = 0x1;
+pub const MAPPING: usize = 0x2;
+pub const FAULT: usize = 0xe;
+pub const RESET: usize = 0xf;
+pub const TITLE: usize = 0x10;
+pub const DEVELOPER: usize = 0x20;
+pub const PUBLISHER: usize = 0x30;
+
+/// Header strings are stored as up to 16 24-bit wide unicode characters.
+pub const STRING_LEN: usize = 16;
@@ -0,0 +1,171 @@
+//! The Pluto instruction set and ROM layout, shared by the assembler and the runtime.
+
+pub mod header;
+pub mod mapping;
+pub mod syscall;
+
+/// Where an instruction gets its operand from, if it has one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OperandKind {
+	None,
+	/// The word following the opcode
+	Word,
+	/// The low 12 bits of the opcode itself
+	Embedded,
+}
+
+macro_rules! instructions {
+	($($name:ident = $opcode:literal, $mnemonic:literal, $operand:ident, $pops:literal -> $pushes:literal;)*) => {
+		#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+		pub enum Op {
+			$($name,)*
+		}
+
+		impl Op {
+			pub const ALL: &'static [Op] = &[$(Op::$name,)*];
+
+			pub fn mnemonic(self) -> &'static str {
+				match self {
+					$(Op::$name => $mnemonic,)*
+				}
+			}
+			/// The opcode word, without any embedded operand.
+			pub fn opcode(self) -> u32 {
+				match self {
+					$(Op::$name => $opcode,)*
+				}
+			}
+			pub fn operand(self) -> OperandKind {
+				match self {
+					$(Op::$name => OperandKind::$operand,)*
+				}
+			}
+			/// The number of values the instruction pops and then pushes.
+			pub fn stack_effect(self) -> (usize, usize) {
+				match self {
+					$(Op::$name => ($pops, $pushes),)*
+				}
+			}
+			/// Decodes an opcode word that has no embedded operand.
+			fn decode_exact(word: u32) -> Option<Self> {
+				match word {
+					$($opcode => Some(Op::$name),)*
+					_ => None,
+				}
+			}
+		}
+	};
+}
+
+instructions! {
+	// Stack manipulation
+	Push = 0x001000, "push", Word, 0 -> 1;
+	Drop = 0x001001, "drop", None, 1 -> 0;
+	Peek = 0x000000, "peek", Embedded, 0 -> 1;
+	Load = 0x001002, "load", None, 1 -> 1;
+	Stor = 0x001003, "stor", None, 2 -> 0;
+
+	// Math
+	Neg = 0x002000, "neg", None, 1 -> 1;
+	Add = 0x002001, "add", None, 2 -> 1;
+	Sub = 0x002002, "sub", None, 2 -> 1;
+	Mul = 0x002003, "mul", None, 2 -> 1;
+	Udiv = 0x002005, "udiv", None, 2 -> 1;
+	Sdiv = 0x002006, "sdiv", None, 2 -> 1;
+	Mod = 0x002008, "mod", None, 2 -> 1;
+	Rem = 0x002009, "rem", None, 2 -> 1;
+	Not = 0x00200b, "not", None, 1 -> 1;
+	And = 0x00200c, "and", None, 2 -> 1;
+	Or = 0x00200d, "or", None, 2 -> 1;
+	Xor = 0x00200e, "xor", None, 2 -> 1;
+
+	// Comparisons
+	Eq = 0x003000, "eq", None, 2 -> 1;
+	Ne = 0x003001, "ne", None, 2 -> 1;
+	Ult = 0x003002, "ult", None, 2 -> 1;
+	Slt = 0x003003, "slt", None, 2 -> 1;
+	Ugt = 0x003004, "ugt", None, 2 -> 1;
+	Sgt = 0x003005, "sgt", None, 2 -> 1;
+	Ule = 0x003006, "ule", None, 2 -> 1;
+	Sle = 0x003007, "sle", None, 2 -> 1;
+	Uge = 0x003008, "uge", None, 2 -> 1;
+	Sge = 0x003009, "sge", None, 2 -> 1;
+
+	// End of function
+	Ret = 0x004000, "ret", None, 0 -> 0;
+	Jmp = 0x004001, "jmp", None, 1 -> 0;
+	If = 0x004002, "if", None, 3 -> 0;
+	Call = 0x004003, "call", None, 2 -> 0;
+}
+
+impl Op {
+	/// Decodes an opcode word. Any embedded operand is left for the caller to
+	/// extract with `embedded`.
+	pub fn decode(word: u32) -> Option<Self> {
+		Self::decode_exact(word).or_else(|| {
+			Self::ALL.iter().copied().find(|op| {
+				op.operand() == OperandKind::Embedded && word & !EMBEDDED_MASK == op.opcode()
+			})
+		})
+	}
+	/// Looks up an instruction by its mnemonic, ignoring case.
+	pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+		Self::ALL
+			.iter()
+			.copied()
+			.find(|op| op.mnemonic().eq_ignore_ascii_case(mnemonic))
+	}
+	/// Builds the opcode word, folding in the operand if it is embedded.
+	pub fn encode(self, embedded: u32) -> u32 {
+		match self.operand() {
+			OperandKind::Embedded => self.opcode() | (embedded & EMBEDDED_MASK),
+			_ => self.opcode(),
+		}
+	}
+	/// Whether the instruction ends a function by scheduling what runs next.
+	pub fn ends_function(self) -> bool {
+		matches!(self, Op::Ret | Op::Jmp | Op::If | Op::Call)
+	}
+}
+
+/// The largest operand that can be embedded in an opcode.
+pub const EMBEDDED_MASK: u32 = 0xfff;
+
+/// The operand embedded in an opcode word.
+pub fn embedded(word: u32) -> u32 {
+	word & EMBEDDED_MASK
+}
+
+/// Words are 24 bits wide.
+pub const WORD_MASK: u32 = 0xffffff;
+
+/// Reads a word as a two's complement value, sign extending from bit 23.
+pub fn sign_extend(word: u32) -> i32 {
+	((word << 8) as i32) >> 8
+}
+
+/// Packs the number of arguments and returns into a function signature.
+pub fn signature(args: u32, ret: u32) -> u32 {
+	((args & 0xfff) << 12) | (ret & 0xfff)
+}
+
+/// Splits a function signature into its number of arguments and returns.
+pub fn decompose_signature(sig: u32) -> (u32, u32) {
+	(sig >> 12, sig & 0xfff)
+}
+
+/// Reads big-endian 24-bit words, ignoring any trailing partial word.
+pub fn words_from_bytes(bytes: &[u8]) -> Vec<u32> {
+	bytes
+		.chunks_exact(3)
+		.map(|i| u32::from_be_bytes([0, i[0], i[1], i[2]]))
+		.collect()
+}
+
+/// Writes words as big-endian 24-bit values.
+pub fn bytes_from_words(words: &[u32]) -> Vec<u8> {
+	words
+		.iter()
+		.flat_map(|w| vec![(w >> 16) as u8, (w >> 8) as u8, *w as u8])
+		.collect()
+}
@@ -0,0 +1,52 @@
+//! Address space layouts, selected by the mapping word in the header.
+
+/// A range of addresses, in words.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Region {
+	pub start: u32,
+	pub size: u32,
+}
+impl Region {
+	pub fn contains(self, address: u32) -> bool {
+		address >= self.start && address - self.start < self.size
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Mapping {
+	/// Where the ROM is loaded, read only. The size is the largest ROM allowed.
+	pub rom: Region,
+	/// Writable memory, zeroed at startup
+	pub ram: Option<Region>,
+	/// Reserved for memory mapped devices
+	pub io: Option<Region>,
+}
+
+/// The layout for a mapping mode, if it exists.
+pub fn layout(mapping: u32) -> Option<Mapping> {
+	match mapping {
+		0 => Some(Mapping {
+			rom: Region {
+				start: 0,
+				size: 0x1000000,
+			},
+			ram: None,
+			io: None,
+		}),
+		1 => Some(Mapping {
+			rom: Region {
+				start: 0,
+				size: 0x800000,
+			},
+			ram: Some(Region {
+				start: 0x800000,
+				size: 0x700000,
+			}),
+			io: Some(Region {
+				start: 0xf00000,
+				size: 0x100000,
+			}),
+		}),
+		_ => None,
+	}
+}
@@ -0,0 +1,27 @@
+//! System calls built into the runtime, reached by scheduling a function
+//! pointer that falls inside the header.
+
+use crate::header;
+
+pub const PUTCHAR: u32 = 0x01;
+pub const GETCHAR: u32 = 0x02;
+pub const EXIT: u32 = 0x03;
+pub const RANDOM: u32 = 0x04;
+pub const CLOCK: u32 = 0x05;
+
+/// Whether a function pointer refers to a system call rather than to memory.
+pub fn is_syscall(func_ptr: u32) -> bool {
+	(func_ptr as usize) < header::SIZE
+}
+
+/// The args and returns of a built in system call.
+pub fn signature(number: u32) -> Option<(u32, u32)> {
+	match number {
+		PUTCHAR => Some((1, 0)),
+		GETCHAR => Some((0, 1)),
+		EXIT => Some((1, 0)),
+		RANDOM => Some((0, 1)),
+		CLOCK => Some((0, 1)),
+		_ => None,
+	}
+}
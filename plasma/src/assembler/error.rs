@@ -0,0 +1,141 @@
+use super::source::Span;
+use std::{cmp::Ordering, fmt};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Level {
+	Error,
+	Warning,
+}
+
+pub struct AssembleError {
+	pub level: Level,
+	pub span: Option<Span>,
+	pub message: String,
+	pub expected: Option<String>,
+	pub notes: Vec<(Span, String)>,
+}
+impl AssembleError {
+	pub fn new(span: Span, message: String) -> Self {
+		Self {
+			level: Level::Error,
+			span: Some(span),
+			message,
+			expected: None,
+			notes: Vec::new(),
+		}
+	}
+	/// An error about the program as a whole rather than a particular line.
+	pub fn global(message: String) -> Self {
+		Self {
+			level: Level::Error,
+			span: None,
+			message,
+			expected: None,
+			notes: Vec::new(),
+		}
+	}
+	pub fn warning(span: Span, message: String) -> Self {
+		Self {
+			level: Level::Warning,
+			..Self::new(span, message)
+		}
+	}
+	/// An error for input the grammar could not make sense of.
+	pub fn unexpected(span: Span, expected: Option<&str>) -> Self {
+		let text = span.text();
+		let message = if text.is_empty() {
+			"unexpected end of file".to_string()
+		} else {
+			format!("unexpected `{}`", text)
+		};
+		Self {
+			expected: expected.map(|e| e.to_string()),
+			..Self::new(span, message)
+		}
+	}
+	pub fn with_note(mut self: Self, span: Span, message: &str) -> Self {
+		self.notes.push((span, message.to_string()));
+		self
+	}
+	/// Orders diagnostics by where they occur, with global ones first.
+	pub fn cmp_position(self: &Self, other: &Self) -> Ordering {
+		match (&self.span, &other.span) {
+			(Some(a), Some(b)) => a.cmp_position(b),
+			(a, b) => a.is_some().cmp(&b.is_some()),
+		}
+	}
+}
+
+fn write_snippet(f: &mut fmt::Formatter, span: &Span) -> fmt::Result {
+	let line = span.line().to_string();
+	let gutter = " ".repeat(line.len());
+	writeln!(f)?;
+	writeln!(f, "{}--> {}", gutter, span)?;
+	writeln!(f, "{} |", gutter)?;
+	writeln!(f, "{} | {}", line, span.line_text())?;
+	write!(f, "{} | {}", gutter, span.caret())
+}
+
+impl fmt::Display for AssembleError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.level {
+			Level::Error => write!(f, "error: {}", self.message)?,
+			Level::Warning => write!(f, "warning: {}", self.message)?,
+		}
+		if let Some(expected) = &self.expected {
+			write!(f, ", expected {}", expected)?;
+		}
+		if let Some(span) = &self.span {
+			write_snippet(f, span)?;
+		}
+		for (span, message) in &self.notes {
+			write!(f, "\nnote: {}", message)?;
+			write_snippet(f, span)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::tests::assemble;
+
+	#[test]
+	fn caret_under_the_token() {
+		let assembler = assemble(".reset main\n:main func 0 0\n\tpush 1 frob\n\tret\n");
+		let error = &assembler.errors()[0];
+		let expected = format!(
+			"\
+error: unexpected `frob`, expected instruction
+ --> {}:3:9
+  |
+3 | \tpush 1 frob
+  | \t       ^^^^",
+			error.span.as_ref().unwrap().file().display()
+		);
+		assert_eq!(error.to_string(), expected);
+	}
+
+	#[test]
+	fn notes_and_wide_line_numbers() {
+		let text = format!(".reset main\n:main func 0 0 ret\n{}:main", "\n".repeat(7));
+		let assembler = assemble(&text);
+		let error = &assembler.errors()[0];
+		let file = error.span.as_ref().unwrap().file().display();
+		let expected = format!(
+			"\
+error: label `main` is defined more than once
+  --> {0}:10:2
+   |
+10 | :main
+   |  ^^^^
+note: previously defined here
+ --> {0}:2:2
+  |
+2 | :main func 0 0 ret
+  |  ^^^^",
+			file
+		);
+		assert_eq!(error.to_string(), expected);
+	}
+}
@@ -0,0 +1,76 @@
+use super::source::Span;
+use std::{
+	io::{self, Write},
+	path::Path,
+};
+
+/// Words per row of the listing; longer statements continue on more rows.
+const ROW_WORDS: usize = 4;
+
+/// A source line along with the words it produced.
+pub struct Line {
+	pub index: usize,
+	pub len: usize,
+	pub span: Span,
+	/// For `skip` and `skipto`, whose padding isn't worth printing
+	pub skip: bool,
+}
+
+/// Writes every word's address and value beside the source line it came from,
+/// in address order.
+pub fn write<W: Write>(lines: &[Line], data: &[u32], mut out: W) -> io::Result<()> {
+	let mut lines: Vec<&Line> = lines.iter().collect();
+	lines.sort_by_key(|line| line.index);
+	let mut file: Option<&Path> = None;
+	for line in lines {
+		if file != Some(line.span.file()) {
+			file = Some(line.span.file());
+			writeln!(out, "; {}", line.span.file().display())?;
+		}
+		let source = format!("{:5}  {}", line.span.line(), line.span.line_text());
+		if line.skip {
+			let words = format!("({:#x} words)", line.len);
+			writeln!(out, "{:06x}  {:27}  {}", line.index, words, source)?;
+			continue;
+		}
+		let words = &data[line.index..line.index + line.len];
+		for (row, chunk) in words.chunks(ROW_WORDS).enumerate() {
+			let words: Vec<String> = chunk.iter().map(|w| format!("{:06x}", w)).collect();
+			let address = line.index + row * ROW_WORDS;
+			match row {
+				0 => writeln!(out, "{:06x}  {:27}  {}", address, words.join(" "), source)?,
+				_ => writeln!(out, "{:06x}  {}", address, words.join(" "))?,
+			}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::tests::assemble;
+
+	#[test]
+	fn listing() {
+		let assembler = assemble(
+			".reset main\n:main func 0 0\n\tpush 0x123456\n\tret\nskip 2\nstring \"abcde\"\n",
+		);
+		let mut out = Vec::new();
+		assembler.write_listing(&mut out).unwrap();
+		let out = String::from_utf8(out).unwrap();
+		let (file, rows) = out.split_once('\n').unwrap();
+		assert!(file.starts_with("; ") && file.ends_with("main.pasm"));
+		assert_eq!(
+			rows,
+			"\
+00000f  000040                           1  .reset main
+000040  000000                           2  :main func 0 0
+000041  001000 123456                    3  \tpush 0x123456
+000043  004000                           4  \tret
+000044  (0x2 words)                      5  skip 2
+000046  000061 000062 000063 000064      6  string \"abcde\"
+00004a  000065
+"
+		);
+	}
+}
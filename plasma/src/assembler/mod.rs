@@ -1,114 +1,555 @@
+mod error;
+mod listing;
 mod parser;
+mod source;
+mod symbols;
+mod verify;
 
-use parser::{Address, Instruction, Parser, Statement};
+pub use error::AssembleError;
+use isa::header;
+use listing::Line;
+use parser::{Directive, Expr, Operand, Parser, Statement, StringFormat};
+use source::{Source, Span};
 use std::{
 	collections::HashMap,
-	fs::File,
 	io::{self, Write},
-	path::PathBuf,
+	path::{Path, PathBuf},
+	rc::Rc,
 };
+use symbols::{SymbolTable, Value};
+use verify::FunctionRecord;
 
-struct Label {
-	address: Option<u32>,
-	repl_address: Vec<u32>,
-}
-impl Label {
-	fn new() -> Self {
-		Self {
-			address: None,
-			repl_address: Vec::new(),
-		}
-	}
+/// A word whose value depends on labels, filled in once they are all known.
+struct Fixup {
+	index: usize,
+	expr: Expr,
+	source: Rc<Source>,
 }
 
 pub struct Assembler {
 	data: Vec<u32>,
-	labels: HashMap<String, Label>,
+	symbols: SymbolTable,
+	fixups: Vec<Fixup>,
+	functions: Vec<FunctionRecord>,
+	/// Where each word came from, for the listing
+	lines: Vec<Line>,
+	directives: HashMap<&'static str, Span>,
+	/// Canonical paths of the files currently being assembled, innermost last.
+	include_stack: Vec<PathBuf>,
+	syntax_errors: bool,
+	errors: Vec<AssembleError>,
+	warnings: Vec<AssembleError>,
 }
 
 impl Assembler {
 	pub fn new() -> Self {
+		let mut data = vec![0; header::SIZE];
+		data[0x0] = header::MAGIC;
 		Self {
-			data: Vec::new(),
-			labels: HashMap::new(),
+			data,
+			symbols: SymbolTable::new(),
+			fixups: Vec::new(),
+			functions: Vec::new(),
+			lines: Vec::new(),
+			directives: HashMap::new(),
+			include_stack: Vec::new(),
+			syntax_errors: false,
+			errors: Vec::new(),
+			warnings: Vec::new(),
 		}
 	}
-	pub fn load_file(self: &mut Self, in_path: &PathBuf) {
-		let parser = Parser::new(File::open(in_path).unwrap());
+	pub fn load_file(self: &mut Self, in_path: &Path) -> io::Result<()> {
+		let source = Source::load(in_path)?;
+		let parser = Parser::new(source.clone());
 
+		self.include_stack.push(in_path.canonicalize()?);
 		for s in parser {
-			self.add_statement(s);
+			match s {
+				Ok(s) => self.add_statement(s, &source),
+				Err(e) => {
+					self.syntax_errors = true;
+					self.errors.push(e)
+				}
+			}
 		}
-
-		for (_, record) in self.labels.iter() {
-			for r in record.repl_address.iter() {
-				self.data[*r as usize] = record.address.unwrap();
+		self.include_stack.pop();
+		Ok(())
+	}
+	/// Resolves labels once every file has been loaded.
+	pub fn finish(self: &mut Self) {
+		// Labels defined after a syntax error may have been skipped, so
+		// reporting them as undefined would only be noise.
+		if !self.syntax_errors {
+			self.resolve_labels();
+			if self.errors.is_empty() {
+				self.errors
+					.append(&mut verify::verify(&self.functions, &self.data));
+			}
+			if !self.directives.contains_key("reset") {
+				self.errors.push(AssembleError::global(
+					"no reset vector, add a `.reset` directive pointing at the entry function"
+						.to_string(),
+				));
+			}
+			// An unknown mapping has already been reported
+			let mapping = self.data[header::MAPPING];
+			if let Some(layout) = isa::mapping::layout(mapping) {
+				if self.data.len() > layout.rom.size as usize {
+					self.errors.push(AssembleError::global(format!(
+						"the ROM is {:#x} words, but mapping {} only has room for {:#x}",
+						self.data.len(),
+						mapping,
+						layout.rom.size
+					)));
+				}
 			}
 		}
 	}
-	fn def_label(self: &mut Self, name: String, value: u32) {
-		if !self.labels.contains_key(&name) {
-			self.labels.insert(name.clone(), Label::new());
+	pub fn errors(self: &Self) -> &[AssembleError] {
+		&self.errors
+	}
+	pub fn warnings(self: &Self) -> &[AssembleError] {
+		&self.warnings
+	}
+	fn resolve_labels(self: &mut Self) {
+		let mut errors = Vec::new();
+		for fixup in self.fixups.iter() {
+			if let Some(v) = self
+				.symbols
+				.evaluate(&fixup.expr, &fixup.source, &mut errors)
+			{
+				self.data[fixup.index] = v as u32 & isa::WORD_MASK;
+			}
+		}
+		self.symbols.check_defs(&mut errors);
+		errors.sort_by(AssembleError::cmp_position);
+		self.errors.append(&mut errors);
+		self.warnings.append(&mut self.symbols.unused());
+	}
+	fn def_label(self: &mut Self, name: String, span: Span, value: Value) {
+		if let Err(e) = self.symbols.define(name, span, value) {
+			self.errors.push(e);
 		}
-		self.labels.get_mut(&name).unwrap().address = Some(value);
 	}
-	fn add_address(self: &mut Self, address: Address) {
-		match address {
-			Address::Const(n) => self.data.push(n),
-			Address::Label(name) => {
-				if !self.labels.contains_key(&name) {
-					self.labels.insert(name.clone(), Label::new());
+	fn set_address(self: &mut Self, index: usize, expr: Expr, source: &Rc<Source>) {
+		match expr {
+			Expr::Const(n) => self.data[index] = n,
+			expr => {
+				self.symbols.reference(&expr);
+				self.fixups.push(Fixup {
+					index,
+					expr,
+					source: source.clone(),
+				});
+			}
+		}
+	}
+	fn add_address(self: &mut Self, expr: Expr, source: &Rc<Source>) {
+		self.data.push(0);
+		self.set_address(self.data.len() - 1, expr, source);
+	}
+	fn set_string(self: &mut Self, index: usize, text: String, span: Span) {
+		let chars: Vec<u32> = text.chars().map(|c| c as u32).collect();
+		if chars.len() > header::STRING_LEN {
+			self.errors.push(AssembleError::new(
+				span,
+				format!(
+					"string is {} characters long, but the header only has room for {}",
+					chars.len(),
+					header::STRING_LEN
+				),
+			));
+			return;
+		}
+		self.data[index..index + chars.len()].copy_from_slice(&chars);
+	}
+	fn add_directive(self: &mut Self, directive: Directive, span: Span, source: &Rc<Source>) {
+		if let Directive::Header(start, words) = directive {
+			return self.set_header(start, words, span);
+		}
+		if let Some(previous) = self.directives.get(directive.name()) {
+			self.errors.push(
+				AssembleError::new(
+					span,
+					format!("`.{}` is set more than once", directive.name()),
+				)
+				.with_note(previous.clone(), "previously set here"),
+			);
+			return;
+		}
+		self.directives.insert(directive.name(), span.clone());
+		let (index, len) = match &directive {
+			Directive::Features(_) => (header::FEATURES, 1),
+			Directive::Mapping(_) => (header::MAPPING, 1),
+			Directive::Fault(_) => (header::FAULT, 1),
+			Directive::Reset(_) => (header::RESET, 1),
+			Directive::Title(_) => (header::TITLE, header::STRING_LEN),
+			Directive::Developer(_) => (header::DEVELOPER, header::STRING_LEN),
+			Directive::Publisher(_) => (header::PUBLISHER, header::STRING_LEN),
+			Directive::Header(..) => unreachable!(),
+		};
+		self.lines.push(Line {
+			index,
+			len,
+			span: span.clone(),
+			skip: false,
+		});
+		match directive {
+			Directive::Features(n) => self.data[header::FEATURES] = n,
+			Directive::Mapping(n) => {
+				if isa::mapping::layout(n).is_none() {
+					self.errors.push(AssembleError::new(
+						span,
+						format!("unknown mapping mode {}", n),
+					));
 				}
-				self.labels
-					.get_mut(&name)
-					.unwrap()
-					.repl_address
-					.push(self.data.len() as u32);
-				self.data.push(0);
+				self.data[header::MAPPING] = n;
 			}
+			Directive::Fault(a) => self.set_address(header::FAULT, a, source),
+			Directive::Reset(a) => self.set_address(header::RESET, a, source),
+			Directive::Title(s) => self.set_string(header::TITLE, s, span),
+			Directive::Developer(s) => self.set_string(header::DEVELOPER, s, span),
+			Directive::Publisher(s) => self.set_string(header::PUBLISHER, s, span),
+			Directive::Header(..) => unreachable!(),
 		}
 	}
-	fn add_statement(self: &mut Self, statement: Statement) {
+	/// Writes raw words into the header. Unlike the other directives this can
+	/// be used any number of times, with later words replacing earlier ones.
+	fn set_header(self: &mut Self, start: u32, words: Vec<u32>, span: Span) {
+		let start = start as usize;
+		if start == 0 || start + words.len() > header::SIZE {
+			self.errors.push(AssembleError::new(
+				span,
+				format!(
+					"`.header` words have to fit between 0x1 and {:#x}",
+					header::SIZE - 1
+				),
+			));
+			return;
+		}
+		self.lines.push(Line {
+			index: start,
+			len: words.len(),
+			span,
+			skip: false,
+		});
+		for (i, word) in words.into_iter().enumerate() {
+			self.data[start + i] = word & isa::WORD_MASK;
+		}
+	}
+	fn include(self: &mut Self, path: String, span: Span, source: &Rc<Source>) {
+		let path = source
+			.path()
+			.parent()
+			.unwrap_or_else(|| Path::new(""))
+			.join(path);
+		let result = match path.canonicalize() {
+			Ok(canonical) if self.include_stack.contains(&canonical) => {
+				let start = self.include_stack.iter().position(|p| *p == canonical);
+				let chain: Vec<String> = self.include_stack[start.unwrap()..]
+					.iter()
+					.chain(Some(&canonical))
+					.map(|p| p.display().to_string())
+					.collect();
+				self.errors.push(AssembleError::new(
+					span,
+					format!("include cycle: {}", chain.join(" -> ")),
+				));
+				return;
+			}
+			Ok(_) => self.load_file(&path),
+			Err(e) => Err(e),
+		};
+		if let Err(e) = result {
+			self.errors.push(AssembleError::new(
+				span,
+				format!("cannot include `{}`: {}", path.display(), e),
+			));
+		}
+	}
+	/// Records that the words from `start` on came from the line at `span`.
+	fn list(self: &mut Self, start: usize, span: Span, skip: bool) {
+		let len = self.data.len() - start;
+		if len > 0 {
+			self.lines.push(Line {
+				index: start,
+				len,
+				span,
+				skip,
+			});
+		}
+	}
+	fn add_statement(self: &mut Self, statement: Statement, source: &Rc<Source>) {
 		match statement {
-			Statement::Function(func) => {
-				self.data
-					.push(((func.args & 0xfff) << 12) + (func.ret & 0xfff));
-				for inst in func.block {
-					self.data.push(inst.get_opcode());
-					if let Instruction::Push(a) = inst {
-						self.add_address(a);
+			Statement::Function(func, pos) => {
+				let mut record = FunctionRecord {
+					address: self.data.len() as u32,
+					args: func.args,
+					ret: func.ret,
+					span: Span::from_pos(source.clone(), pos),
+					block: Vec::new(),
+				};
+				self.data.push(isa::signature(func.args, func.ret));
+				self.list(record.address as usize, record.span.clone(), false);
+				for (inst, pos) in func.block {
+					let span = Span::from_pos(source.clone(), pos);
+					let start = self.data.len();
+					record.block.push((inst.clone(), start, span.clone()));
+					self.data.push(inst.opcode());
+					if let Operand::Word(a) = inst.operand {
+						self.add_address(a, source);
 					}
+					self.list(start, span, false);
 				}
+				self.functions.push(record);
 			}
-			Statement::Skip(num) => {
+			Statement::Skip(num, pos) => {
+				let start = self.data.len();
 				for _ in 0..num {
 					self.data.push(0);
 				}
+				self.list(start, Span::from_pos(source.clone(), pos), true);
 			}
-			Statement::SkipTo(address) => {
-				let num = address as usize - self.data.len();
-				if num > 0xffffff {
-					panic!()
+			Statement::SkipTo(address, pos) => {
+				let start = self.data.len();
+				let span = Span::from_pos(source.clone(), pos);
+				if (address as usize) < self.data.len() {
+					self.errors.push(AssembleError::new(
+						span.clone(),
+						format!(
+							"cannot skip back to {:#x}, already at {:#x}",
+							address,
+							self.data.len()
+						),
+					));
 				}
-				for _ in 0..num {
+				self.data.resize(self.data.len().max(address as usize), 0);
+				self.list(start, span, true);
+			}
+			Statement::Word(value, pos) => {
+				let start = self.data.len();
+				self.add_address(value, source);
+				self.list(start, Span::from_pos(source.clone(), pos), false);
+			}
+			Statement::String(text, format, pos) => {
+				let start = self.data.len();
+				let chars: Vec<u32> = text.chars().map(|c| c as u32).collect();
+				if let StringFormat::LengthPrefixed = format {
+					self.data.push(chars.len() as u32);
+				}
+				self.data.extend(chars);
+				if let StringFormat::NulTerminated = format {
 					self.data.push(0);
 				}
+				self.list(start, Span::from_pos(source.clone(), pos), false);
+			}
+			Statement::Label(name, pos) => self.def_label(
+				name,
+				Span::from_pos(source.clone(), pos),
+				Value::Address(self.data.len() as u32),
+			),
+			Statement::Def(name, pos, value) => self.def_label(
+				name,
+				Span::from_pos(source.clone(), pos),
+				Value::Expr(value, source.clone()),
+			),
+			Statement::Include(path, pos) => {
+				self.include(path, Span::from_pos(source.clone(), pos), source)
+			}
+			Statement::Directive(directive, pos) => {
+				self.add_directive(directive, Span::from_pos(source.clone(), pos), source)
 			}
-			Statement::Word(value) => self.add_address(value),
-			Statement::Label(name) => self.def_label(name, self.data.len() as u32),
-			Statement::Def(name, value) => self.def_label(name, value),
 		}
 	}
-	pub fn write(self: Self, mut out: File) -> io::Result<()> {
-		let data = convert_24_bit(self.data);
-		out.write_all(&data)?;
+	/// The assembled ROM, one word per element.
+	pub fn words(self: &Self) -> &[u32] {
+		&self.data
+	}
+	pub fn write<W: Write>(self: &Self, mut out: W) -> io::Result<()> {
+		out.write_all(&isa::bytes_from_words(&self.data))
+	}
+	/// Writes every label and `def` with its value, one per line in the
+	/// format pluto's debugger reads, ordered by value.
+	pub fn write_symbols<W: Write>(self: &mut Self, mut out: W) -> io::Result<()> {
+		for (value, name, kind) in self.symbols.values() {
+			writeln!(out, "{:#08x} {} {}", value, name, kind)?;
+		}
 		Ok(())
 	}
+	/// Writes each word's address and value beside the line of source that
+	/// produced it.
+	pub fn write_listing<W: Write>(self: &Self, out: W) -> io::Result<()> {
+		listing::write(&self.lines, &self.data, out)
+	}
+}
+
+impl Default for Assembler {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
-fn convert_24_bit(words: Vec<u32>) -> Vec<u8> {
-	words
-		.iter()
-		.flat_map(|w| vec![(w >> 16) as u8, (w >> 8) as u8, (*w) as u8])
-		.collect()
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use std::{
+		env, fs, process,
+		sync::atomic::{AtomicUsize, Ordering},
+	};
+
+	/// Writes `files` to a new temporary directory and assembles the first.
+	pub fn assemble_files(files: &[(&str, &str)]) -> Assembler {
+		static COUNT: AtomicUsize = AtomicUsize::new(0);
+		let dir = env::temp_dir().join(format!(
+			"plasma-test-{}-{}",
+			process::id(),
+			COUNT.fetch_add(1, Ordering::Relaxed)
+		));
+		for (name, text) in files {
+			let path = dir.join(name);
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::write(path, text).unwrap();
+		}
+		let mut assembler = Assembler::new();
+		assembler.load_file(&dir.join(files[0].0)).unwrap();
+		assembler.finish();
+		fs::remove_dir_all(&dir).unwrap();
+		assembler
+	}
+	pub fn assemble(text: &str) -> Assembler {
+		assemble_files(&[("main.pasm", text)])
+	}
+	pub fn errors(assembler: &Assembler) -> Vec<&str> {
+		assembler
+			.errors()
+			.iter()
+			.map(|e| e.message.as_str())
+			.collect()
+	}
+	pub fn warnings(assembler: &Assembler) -> Vec<&str> {
+		assembler
+			.warnings()
+			.iter()
+			.map(|e| e.message.as_str())
+			.collect()
+	}
+
+	#[test]
+	fn reset_is_required() {
+		let assembler = assemble(":main func 0 0 ret\n");
+		assert_eq!(
+			errors(&assembler),
+			["no reset vector, add a `.reset` directive pointing at the entry function"]
+		);
+	}
+
+	#[test]
+	fn header_from_directives() {
+		let assembler = assemble(
+			".reset main\n.mapping 1\n.title \"Sixteen chars!!!\"\n.header 0x3 7 8\n:main func 0 0 ret\n",
+		);
+		assert!(errors(&assembler).is_empty());
+		let words = assembler.words();
+		assert_eq!(words[0], header::MAGIC);
+		assert_eq!(words[header::MAPPING], 1);
+		assert_eq!(&words[0x3..0x5], [7, 8]);
+		assert_eq!(words[header::RESET], 0x40);
+		let title: String = words[header::TITLE..header::TITLE + header::STRING_LEN]
+			.iter()
+			.map(|c| std::char::from_u32(*c).unwrap())
+			.collect();
+		assert_eq!(title, "Sixteen chars!!!");
+		assert_eq!(words[header::DEVELOPER], 0);
+	}
+
+	#[test]
+	fn titles_fit_in_the_header() {
+		let assembler = assemble(".reset main\n.title \"Seventeen chars!!\"\n:main func 0 0 ret\n");
+		assert_eq!(
+			errors(&assembler),
+			["string is 17 characters long, but the header only has room for 16"]
+		);
+	}
+
+	#[test]
+	fn directives_are_set_once() {
+		let assembler = assemble(".reset main\n.reset main\n:main func 0 0 ret\n");
+		assert_eq!(errors(&assembler), ["`.reset` is set more than once"]);
+	}
+
+	#[test]
+	fn string_formats() {
+		let assembler = assemble(
+			".reset main\n:main func 0 0 ret\nstring \"a\\tb\"\nstringz \"c\"\npstring \"\\u{1F600}\\\"\"\n",
+		);
+		assert!(errors(&assembler).is_empty());
+		assert_eq!(
+			&assembler.words()[0x42..],
+			[
+				'a' as u32,
+				'\t' as u32,
+				'b' as u32,
+				'c' as u32,
+				0,
+				2,
+				0x1f600,
+				'"' as u32
+			]
+		);
+	}
+
+	#[test]
+	fn includes() {
+		let assembler = assemble_files(&[
+			(
+				"main.pasm",
+				".reset main\ninclude \"lib/lib.pasm\"\n:main func 0 0 push helper jmp\n",
+			),
+			("lib/lib.pasm", ":helper func 0 0 ret\n"),
+		]);
+		assert!(errors(&assembler).is_empty());
+		assert_eq!(assembler.words()[header::SIZE + 4], header::SIZE as u32);
+	}
+
+	#[test]
+	fn include_cycles() {
+		let assembler = assemble_files(&[
+			(
+				"a.pasm",
+				".reset main\n:main func 0 0 ret\ninclude \"b.pasm\"\n",
+			),
+			("b.pasm", "include \"c.pasm\"\n"),
+			("c.pasm", "include \"b.pasm\"\n"),
+		]);
+		let errors = errors(&assembler);
+		assert_eq!(errors.len(), 1);
+		let chain: Vec<&str> = errors[0]
+			.strip_prefix("include cycle: ")
+			.unwrap()
+			.split(" -> ")
+			.collect();
+		assert_eq!(chain.len(), 3);
+		assert!(chain[0].ends_with("b.pasm") && chain[2] == chain[0]);
+		assert!(chain[1].ends_with("c.pasm"));
+	}
+
+	#[test]
+	fn missing_includes() {
+		let assembler = assemble(".reset main\n:main func 0 0 ret\ninclude \"missing.pasm\"\n");
+		assert_eq!(errors(&assembler).len(), 1);
+		assert!(errors(&assembler)[0].starts_with("cannot include `"));
+	}
+
+	#[test]
+	fn symbol_file() {
+		let mut assembler = assemble(
+			".reset main\ndef SIZE 3\ndef NEG -1\n:main func 0 0 push SIZE push NEG ret\n:end\n",
+		);
+		let mut out = Vec::new();
+		assembler.write_symbols(&mut out).unwrap();
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			"0x000003 SIZE def\n0x000040 main label\n0x000046 end label\n0xffffff NEG def\n"
+		);
+	}
 }
@@ -1,152 +1,426 @@
-use super::{Address, Function, Instruction, Statement};
+use super::{BinOp, Directive, Expr, Function, Instruction, Operand, Pos, Statement, StringFormat};
+use isa::{Op, OperandKind};
 use nom::{
-	alt,
 	branch::alt,
-	bytes::complete::{tag, tag_no_case, take_while1},
+	bytes::complete::{is_not, tag, tag_no_case, take_while, take_while1},
 	character::{
-		complete::{digit1, hex_digit1, multispace0},
-		is_alphabetic,
+		complete::{digit1, hex_digit1, multispace1},
+		is_alphabetic, is_alphanumeric,
 	},
-	is_not,
-	multi::many0,
-	named, pair, preceded,
-	sequence::{preceded, tuple},
-	tag, tag_no_case, tuple, value, IResult,
+	combinator::{cut, map, opt, recognize, value, verify},
+	error::{context, ErrorKind, ParseError},
+	multi::{many0, many1},
+	sequence::{pair, preceded, tuple},
+	Err, IResult,
 };
 use std::str::from_utf8;
 
-named!(comment, value!(&[], pair!(tag!("#"), is_not!("\n\r"))));
+/// Keeps track of how far into the input the parser got before failing and
+/// what it was expecting there, so the error can point at the right token.
+pub struct GrammarError {
+	pub remaining: usize,
+	pub expected: Option<&'static str>,
+}
+impl ParseError<&[u8]> for GrammarError {
+	fn from_error_kind(input: &[u8], _: ErrorKind) -> Self {
+		Self {
+			remaining: input.len(),
+			expected: None,
+		}
+	}
+	fn append(_: &[u8], _: ErrorKind, other: Self) -> Self {
+		other
+	}
+	fn or(self: Self, other: Self) -> Self {
+		// The error that got furthest is the most useful one to report
+		if other.remaining < self.remaining
+			|| (other.remaining == self.remaining && self.expected.is_none())
+		{
+			other
+		} else {
+			self
+		}
+	}
+	fn add_context(_: &[u8], ctx: &'static str, mut other: Self) -> Self {
+		if other.expected.is_none() {
+			other.expected = Some(ctx);
+		}
+		other
+	}
+}
+
+fn comment(i: &[u8]) -> IResult<&[u8], &[u8], GrammarError> {
+	recognize(pair(tag("#"), opt(is_not("\n\r"))))(i)
+}
 
-named!(ws, alt!(multispace0 | comment));
+pub fn ws(i: &[u8]) -> IResult<&[u8], &[u8], GrammarError> {
+	recognize(many0(alt((multispace1, comment))))(i)
+}
+
+fn pos(i: &[u8]) -> IResult<&[u8], Pos, GrammarError> {
+	Ok((i, Pos(i.len())))
+}
 
-fn decimal(i: &[u8]) -> IResult<&[u8], u32> {
+fn too_large(i: &[u8]) -> Err<GrammarError> {
+	Err::Failure(GrammarError {
+		remaining: i.len(),
+		expected: Some("number that fits in 24 bits"),
+	})
+}
+fn decimal(i: &[u8]) -> IResult<&[u8], u32, GrammarError> {
 	let (r, n) = digit1(i)?;
-	Ok((r, u32::from_str_radix(from_utf8(n).unwrap(), 10).unwrap()))
+	match from_utf8(n).unwrap().parse() {
+		Ok(n) if n <= 0xffffff => Ok((r, n)),
+		_ => Err(too_large(i)),
+	}
 }
-fn hex(i: &[u8]) -> IResult<&[u8], u32> {
+fn hex(i: &[u8]) -> IResult<&[u8], u32, GrammarError> {
 	let (r, n) = preceded(tag_no_case("0x"), hex_digit1)(i)?;
-	Ok((r, u32::from_str_radix(from_utf8(n).unwrap(), 16).unwrap()))
+	match u32::from_str_radix(from_utf8(n).unwrap(), 16) {
+		Ok(n) if n <= 0xffffff => Ok((r, n)),
+		_ => Err(too_large(i)),
+	}
 }
-named!(number<u32>, preceded!(ws, alt!(hex | decimal)));
 
-macro_rules! simple_inst {
-	($i:expr, $name:expr, $opcode:expr) => {
-		value!($i, $opcode, tuple!(ws, tag_no_case!($name)))
+/// The longest prefix of the input that is valid UTF-8.
+fn valid_utf8(i: &[u8]) -> &str {
+	match from_utf8(i) {
+		Ok(s) => s,
+		Err(e) => from_utf8(&i[..e.valid_up_to()]).unwrap(),
+	}
+}
+/// Reads one character, which may be an escape sequence, returning it along
+/// with the number of bytes it took up.
+fn escaped_char(s: &str) -> Option<(char, usize)> {
+	let mut chars = s.chars();
+	match chars.next()? {
+		'\\' => {
+			let c = match chars.next()? {
+				'n' => '\n',
+				'r' => '\r',
+				't' => '\t',
+				'0' => '\0',
+				'\\' => '\\',
+				'"' => '"',
+				'\'' => '\'',
+				'u' => {
+					// \u{XXXX}
+					let rest = s[2..].strip_prefix('{')?;
+					let end = rest.find('}')?;
+					let c = u32::from_str_radix(&rest[..end], 16)
+						.ok()
+						.and_then(std::char::from_u32)?;
+					return Some((c, end + 4));
+				}
+				_ => return None,
+			};
+			Some((c, 2))
+		}
+		c => Some((c, c.len_utf8())),
+	}
+}
+fn literal_failure(i: &[u8], expected: &'static str) -> Err<GrammarError> {
+	Err::Failure(GrammarError {
+		remaining: i.len(),
+		expected: Some(expected),
+	})
+}
+fn character(i: &[u8]) -> IResult<&[u8], u32, GrammarError> {
+	let (i, _) = tag("'")(i)?;
+	let (c, len) = match valid_utf8(i).chars().next() {
+		Some('\'') | Some('\n') | Some('\r') | None => return Err(literal_failure(i, "character")),
+		_ => escaped_char(valid_utf8(i)).ok_or_else(|| literal_failure(i, "escape sequence"))?,
 	};
+	let (r, _) = cut(context("closing `'`", tag("'")))(&i[len..])?;
+	Ok((r, c as u32))
+}
+fn string(i: &[u8]) -> IResult<&[u8], String, GrammarError> {
+	let (i, _) = preceded(ws, context("string", tag("\"")))(i)?;
+	let text = valid_utf8(i);
+	let mut out = String::new();
+	let mut at = 0;
+	loop {
+		match text[at..].chars().next() {
+			Some('"') => return Ok((&i[at + 1..], out)),
+			Some('\n') | Some('\r') | None => {
+				return Err(literal_failure(&i[at..], "closing `\"`"))
+			}
+			_ => match escaped_char(&text[at..]) {
+				Some((c, len)) => {
+					out.push(c);
+					at += len;
+				}
+				None => return Err(literal_failure(&i[at..], "escape sequence")),
+			},
+		}
+	}
 }
 
-fn address_const(i: &[u8]) -> IResult<&[u8], Address> {
+fn number(i: &[u8]) -> IResult<&[u8], u32, GrammarError> {
+	preceded(ws, context("number", alt((hex, decimal, character))))(i)
+}
+
+fn expr_const(i: &[u8]) -> IResult<&[u8], Expr, GrammarError> {
 	let (r, n) = number(i)?;
-	Ok((r, Address::Const(n)))
+	Ok((r, Expr::Const(n)))
 }
-fn address_label(i: &[u8]) -> IResult<&[u8], Address> {
-	let (r, n) = preceded(ws, label)(i)?;
-	Ok((r, Address::Label(n)))
+fn expr_label(i: &[u8]) -> IResult<&[u8], Expr, GrammarError> {
+	let (r, (p, n)) = preceded(ws, pair(pos, label))(i)?;
+	Ok((r, Expr::Label(n, p)))
 }
-named!(address<Address>, alt!(address_const | address_label));
-
-fn push(i: &[u8]) -> IResult<&[u8], Instruction> {
-	let (r, (_, _, a)) = tuple((ws, tag_no_case("push"), address))(i)?;
-	Ok((r, Instruction::Push(a)))
+fn expr_neg(i: &[u8]) -> IResult<&[u8], Expr, GrammarError> {
+	let (r, (p, e)) = preceded(ws, pair(pos, preceded(tag("-"), cut(unary))))(i)?;
+	Ok((r, Expr::Neg(Box::new(e), p)))
 }
-
-fn peek(i: &[u8]) -> IResult<&[u8], Instruction> {
-	let (r, (_, _, n)) = tuple((ws, tag_no_case("peek"), number))(i)?;
-	Ok((r, Instruction::Peek(n)))
+fn expr_paren(i: &[u8]) -> IResult<&[u8], Expr, GrammarError> {
+	let (r, (_, _, e, _, _)) =
+		tuple((ws, tag("("), cut(expr), ws, cut(context("`)`", tag(")")))))(i)?;
+	Ok((r, e))
+}
+fn unary(i: &[u8]) -> IResult<&[u8], Expr, GrammarError> {
+	alt((expr_neg, expr_paren, expr_const, expr_label))(i)
 }
 
-named!(
-	stack<Instruction>,
-	alt!(
-		push | simple_inst!("drop", Instruction::Drop)
-			| simple_inst!("load", Instruction::Load)
-			| peek | simple_inst!("stor", Instruction::Stor)
-	)
-);
-
-named!(
-	math<Instruction>,
-	alt!(
-		simple_inst!("neg", Instruction::Neg)
-			| simple_inst!("add", Instruction::Add)
-			| simple_inst!("sub", Instruction::Sub)
-			| simple_inst!("mul", Instruction::Mul)
-			| simple_inst!("udiv", Instruction::Udiv)
-			| simple_inst!("sdiv", Instruction::Sdiv)
-			| simple_inst!("mod", Instruction::Mod)
-			| simple_inst!("rem", Instruction::Rem)
-			| simple_inst!("not", Instruction::Not)
-			| simple_inst!("and", Instruction::And)
-			| simple_inst!("or", Instruction::Or)
-			| simple_inst!("xor", Instruction::Xor)
-	)
-);
-
-named!(
-	comp<Instruction>,
-	alt!(
-		simple_inst!("eq", Instruction::Eq)
-			| simple_inst!("ne", Instruction::Ne)
-			| simple_inst!("ult", Instruction::Ult)
-			| simple_inst!("slt", Instruction::Slt)
-			| simple_inst!("ugt", Instruction::Ugt)
-			| simple_inst!("sgt", Instruction::Sgt)
-			| simple_inst!("ule", Instruction::Ule)
-			| simple_inst!("sle", Instruction::Sle)
-			| simple_inst!("uge", Instruction::Uge)
-			| simple_inst!("sge", Instruction::Sge)
-	)
-);
-
-named!(
-	end<Instruction>,
-	alt!(
-		simple_inst!("ret", Instruction::Ret)
-			| simple_inst!("jmp", Instruction::Jmp)
-			| simple_inst!("if", Instruction::If)
-			| simple_inst!("call", Instruction::Call)
+type ExprParser = fn(&[u8]) -> IResult<&[u8], Expr, GrammarError>;
+
+/// Parses a left associative chain of operands joined by any of `ops`.
+fn binary<'a>(
+	i: &'a [u8],
+	ops: &[(&'static str, BinOp)],
+	operand: ExprParser,
+) -> IResult<&'a [u8], Expr, GrammarError> {
+	let (mut i, mut lhs) = operand(i)?;
+	'chain: loop {
+		let (r, _) = ws(i)?;
+		for (symbol, op) in ops {
+			if let Ok((rest, _)) = tag::<_, _, GrammarError>(*symbol)(r) {
+				let (rest, rhs) = cut(operand)(rest)?;
+				lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs), Pos(r.len()));
+				i = rest;
+				continue 'chain;
+			}
+		}
+		return Ok((i, lhs));
+	}
+}
+fn product(i: &[u8]) -> IResult<&[u8], Expr, GrammarError> {
+	binary(
+		i,
+		&[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Mod)],
+		unary,
 	)
-);
+}
+fn sum(i: &[u8]) -> IResult<&[u8], Expr, GrammarError> {
+	binary(i, &[("+", BinOp::Add), ("-", BinOp::Sub)], product)
+}
+fn shift(i: &[u8]) -> IResult<&[u8], Expr, GrammarError> {
+	binary(i, &[("<<", BinOp::Shl), (">>", BinOp::Shr)], sum)
+}
+fn bit_and(i: &[u8]) -> IResult<&[u8], Expr, GrammarError> {
+	binary(i, &[("&", BinOp::And)], shift)
+}
+fn bit_xor(i: &[u8]) -> IResult<&[u8], Expr, GrammarError> {
+	binary(i, &[("^", BinOp::Xor)], bit_and)
+}
+fn bit_or(i: &[u8]) -> IResult<&[u8], Expr, GrammarError> {
+	binary(i, &[("|", BinOp::Or)], bit_xor)
+}
+fn expr(i: &[u8]) -> IResult<&[u8], Expr, GrammarError> {
+	context("expression", bit_or)(i)
+}
 
-fn function(i: &[u8]) -> IResult<&[u8], Statement> {
-	let (r, (_, args, ret, mut block, end)) = tuple((
+fn mnemonic(i: &[u8]) -> IResult<&[u8], Op, GrammarError> {
+	let (r, word) = take_while1(is_alphabetic)(i)?;
+	match Op::from_mnemonic(from_utf8(word).unwrap()) {
+		Some(op) => Ok((r, op)),
+		None => Err(Err::Error(GrammarError::from_error_kind(i, ErrorKind::Tag))),
+	}
+}
+fn instruction(i: &[u8]) -> IResult<&[u8], Instruction, GrammarError> {
+	let (r, op) = preceded(ws, mnemonic)(i)?;
+	let (r, operand) = match op.operand() {
+		OperandKind::None => (r, Operand::None),
+		OperandKind::Word => map(cut(expr), Operand::Word)(r)?,
+		OperandKind::Embedded => map(cut(number), Operand::Embedded)(r)?,
+	};
+	Ok((r, Instruction { op, operand }))
+}
+
+fn located_inst(i: &[u8]) -> IResult<&[u8], (Instruction, Pos), GrammarError> {
+	let body = verify(instruction, |inst: &Instruction| !inst.op.ends_function());
+	let (r, (p, inst)) = preceded(ws, pair(pos, body))(i)?;
+	Ok((r, (inst, p)))
+}
+fn located_end(i: &[u8]) -> IResult<&[u8], (Instruction, Pos), GrammarError> {
+	let end = verify(instruction, |inst: &Instruction| inst.op.ends_function());
+	let (r, (p, inst)) = preceded(ws, pair(pos, context("instruction", end)))(i)?;
+	Ok((r, (inst, p)))
+}
+fn function(i: &[u8]) -> IResult<&[u8], Statement, GrammarError> {
+	let (r, (p, _, (args, ret, mut block, end))) = tuple((
+		pos,
 		tag_no_case("func"),
-		number,
-		number,
-		many0(alt((stack, math, comp))),
-		end,
+		cut(tuple((number, number, many0(located_inst), located_end))),
 	))(i)?;
 	block.push(end);
-	Ok((r, Statement::Function(Function { args, ret, block })))
+	Ok((r, Statement::Function(Function { args, ret, block }, p)))
 }
 
-fn skip(i: &[u8]) -> IResult<&[u8], Statement> {
-	let (r, v) = preceded(tag_no_case("skip"), number)(i)?;
-	Ok((r, Statement::Skip(v)))
+fn skip(i: &[u8]) -> IResult<&[u8], Statement, GrammarError> {
+	let (r, (p, v)) = pair(pos, preceded(tag_no_case("skip"), cut(number)))(i)?;
+	Ok((r, Statement::Skip(v, p)))
 }
-fn skip_to(i: &[u8]) -> IResult<&[u8], Statement> {
-	let (r, v) = preceded(tag_no_case("skipto"), number)(i)?;
-	Ok((r, Statement::SkipTo(v)))
+fn skip_to(i: &[u8]) -> IResult<&[u8], Statement, GrammarError> {
+	let (r, (p, v)) = pair(pos, preceded(tag_no_case("skipto"), cut(number)))(i)?;
+	Ok((r, Statement::SkipTo(v, p)))
 }
-fn word(i: &[u8]) -> IResult<&[u8], Statement> {
-	let (r, v) = preceded(tag_no_case("word"), address)(i)?;
-	Ok((r, Statement::Word(v)))
+fn word(i: &[u8]) -> IResult<&[u8], Statement, GrammarError> {
+	let (r, (p, v)) = pair(pos, preceded(tag_no_case("word"), cut(expr)))(i)?;
+	Ok((r, Statement::Word(v, p)))
 }
 
-fn label(i: &[u8]) -> IResult<&[u8], String> {
-	let (r, s) = take_while1(|i| is_alphabetic(i) || i == b'_')(i)?;
+fn label(i: &[u8]) -> IResult<&[u8], String, GrammarError> {
+	let (r, s) = context(
+		"label",
+		recognize(pair(
+			take_while1(|i| is_alphabetic(i) || i == b'_'),
+			take_while(|i| is_alphanumeric(i) || i == b'_'),
+		)),
+	)(i)?;
 	Ok((r, from_utf8(s).unwrap().to_string()))
 }
-fn label_def(i: &[u8]) -> IResult<&[u8], Statement> {
-	let (r, s) = preceded(tag(":"), label)(i)?;
-	Ok((r, Statement::Label(s)))
+fn label_def(i: &[u8]) -> IResult<&[u8], Statement, GrammarError> {
+	let (r, (p, s)) = preceded(tag(":"), cut(pair(pos, label)))(i)?;
+	Ok((r, Statement::Label(s, p)))
+}
+fn def(i: &[u8]) -> IResult<&[u8], Statement, GrammarError> {
+	let (r, (_, (_, p, l, a))) = pair(tag_no_case("def"), cut(tuple((ws, pos, label, expr))))(i)?;
+	Ok((r, Statement::Def(l, p, a)))
+}
+
+fn string_statement(i: &[u8]) -> IResult<&[u8], Statement, GrammarError> {
+	let (r, (p, format, s)) = tuple((
+		pos,
+		alt((
+			value(StringFormat::NulTerminated, tag_no_case("stringz")),
+			value(StringFormat::LengthPrefixed, tag_no_case("pstring")),
+			value(StringFormat::Plain, tag_no_case("string")),
+		)),
+		cut(string),
+	))(i)?;
+	Ok((r, Statement::String(s, format, p)))
+}
+
+fn include(i: &[u8]) -> IResult<&[u8], Statement, GrammarError> {
+	let (r, (p, v)) = pair(pos, preceded(tag_no_case("include"), cut(string)))(i)?;
+	Ok((r, Statement::Include(v, p)))
+}
+
+fn features(i: &[u8]) -> IResult<&[u8], Directive, GrammarError> {
+	let (r, v) = preceded(tag_no_case("features"), cut(number))(i)?;
+	Ok((r, Directive::Features(v)))
+}
+fn mapping(i: &[u8]) -> IResult<&[u8], Directive, GrammarError> {
+	let (r, v) = preceded(tag_no_case("mapping"), cut(number))(i)?;
+	Ok((r, Directive::Mapping(v)))
+}
+fn fault(i: &[u8]) -> IResult<&[u8], Directive, GrammarError> {
+	let (r, v) = preceded(tag_no_case("fault"), cut(expr))(i)?;
+	Ok((r, Directive::Fault(v)))
+}
+fn reset(i: &[u8]) -> IResult<&[u8], Directive, GrammarError> {
+	let (r, v) = preceded(tag_no_case("reset"), cut(expr))(i)?;
+	Ok((r, Directive::Reset(v)))
+}
+fn title(i: &[u8]) -> IResult<&[u8], Directive, GrammarError> {
+	let (r, v) = preceded(tag_no_case("title"), cut(string))(i)?;
+	Ok((r, Directive::Title(v)))
+}
+fn developer(i: &[u8]) -> IResult<&[u8], Directive, GrammarError> {
+	let (r, v) = preceded(tag_no_case("developer"), cut(string))(i)?;
+	Ok((r, Directive::Developer(v)))
+}
+fn publisher(i: &[u8]) -> IResult<&[u8], Directive, GrammarError> {
+	let (r, v) = preceded(tag_no_case("publisher"), cut(string))(i)?;
+	Ok((r, Directive::Publisher(v)))
+}
+fn header(i: &[u8]) -> IResult<&[u8], Directive, GrammarError> {
+	let (r, (start, words)) = preceded(tag_no_case("header"), cut(pair(number, many1(number))))(i)?;
+	Ok((r, Directive::Header(start, words)))
 }
-fn def(i: &[u8]) -> IResult<&[u8], Statement> {
-	let (r, (_, _, l, a)) = tuple((tag_no_case("def"), ws, label, number))(i)?;
-	Ok((r, Statement::Def(l, a)))
+fn directive(i: &[u8]) -> IResult<&[u8], Statement, GrammarError> {
+	let (r, (p, _, d)) = tuple((
+		pos,
+		tag("."),
+		cut(context(
+			"directive",
+			alt((
+				features, mapping, fault, reset, title, developer, publisher, header,
+			)),
+		)),
+	))(i)?;
+	Ok((r, Statement::Directive(d, p)))
 }
 
-pub fn statement(i: &[u8]) -> IResult<&[u8], Statement> {
-	let (r, stat) = preceded(ws, alt((function, skip, skip_to, word, label_def, def)))(i)?;
+pub fn statement(i: &[u8]) -> IResult<&[u8], Statement, GrammarError> {
+	let (r, stat) = preceded(
+		ws,
+		context(
+			"statement",
+			alt((
+				function,
+				skip_to,
+				skip,
+				word,
+				string_statement,
+				label_def,
+				def,
+				include,
+				directive,
+			)),
+		),
+	)(i)?;
 	Ok((r, stat))
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_string(text: &str) -> Option<String> {
+		match string(text.as_bytes()) {
+			Ok(([], s)) => Some(s),
+			_ => None,
+		}
+	}
+
+	fn parse_char(text: &str) -> Option<u32> {
+		match number(text.as_bytes()) {
+			Ok(([], c)) => Some(c),
+			_ => None,
+		}
+	}
+
+	#[test]
+	fn string_escapes() {
+		assert_eq!(
+			parse_string(r#""a\n\r\t\0\\\"\'b""#).unwrap(),
+			"a\n\r\t\0\\\"'b"
+		);
+		assert_eq!(parse_string(r#""\u{1F600}\u{41}""#).unwrap(), "\u{1F600}A");
+		assert_eq!(parse_string("\"\u{e9}t\u{e9}\"").unwrap(), "\u{e9}t\u{e9}");
+		assert_eq!(parse_string(r#""\q""#), None);
+		assert_eq!(parse_string(r#""\u{d800}""#), None);
+		assert_eq!(parse_string("\"open\n\""), None);
+	}
+
+	#[test]
+	fn characters() {
+		assert_eq!(parse_char("'a'"), Some('a' as u32));
+		assert_eq!(parse_char(r"'\n'"), Some(10));
+		assert_eq!(parse_char(r"'\''"), Some('\'' as u32));
+		assert_eq!(parse_char("'\u{e9}'"), Some(0xe9));
+		assert_eq!(parse_char("''"), None);
+		assert_eq!(parse_char("'ab'"), None);
+	}
+}
@@ -1,83 +1,58 @@
 mod grammar;
 
-use grammar::statement;
-use std::io::Read;
+use super::{
+	error::AssembleError,
+	source::{Pos, Source, Span},
+};
+use grammar::{statement, ws};
+use isa::Op;
+use std::rc::Rc;
 
-#[derive(Clone)]
-pub enum Address {
-	Const(u32),
-	Label(String),
-}
-
-#[derive(Clone)]
-pub enum Instruction {
-	Push(Address),
-	Drop,
-	Peek(u32),
-	Load,
-	Stor,
-	Neg,
+#[derive(Clone, Copy)]
+pub enum BinOp {
 	Add,
 	Sub,
 	Mul,
-	Udiv,
-	Sdiv,
+	Div,
 	Mod,
-	Rem,
-	Not,
 	And,
 	Or,
 	Xor,
-	Eq,
-	Ne,
-	Ult,
-	Slt,
-	Ugt,
-	Sgt,
-	Ule,
-	Sle,
-	Uge,
-	Sge,
-	Ret,
-	Jmp,
-	If,
-	Call,
+	Shl,
+	Shr,
+}
+
+/// A constant expression, evaluated once every label has an address.
+/// Operators carry their position so evaluation errors can point at them.
+#[derive(Clone)]
+pub enum Expr {
+	Const(u32),
+	Label(String, Pos),
+	Neg(Box<Expr>, Pos),
+	Binary(BinOp, Box<Expr>, Box<Expr>, Pos),
+}
+
+/// An instruction's operand, as given in the source.
+#[derive(Clone)]
+pub enum Operand {
+	None,
+	/// Placed in the word after the opcode, once its labels are resolved
+	Word(Expr),
+	/// Folded into the opcode itself
+	Embedded(u32),
+}
+
+#[derive(Clone)]
+pub struct Instruction {
+	pub op: Op,
+	pub operand: Operand,
 }
 
 impl Instruction {
-	pub fn get_opcode(self: &Self) -> u32 {
-		match self {
-			Instruction::Push(_) => 0x001000,
-			Instruction::Drop => 0x001001,
-			Instruction::Peek(x) => *x,
-			Instruction::Load => 0x001002,
-			Instruction::Stor => 0x001003,
-			Instruction::Neg => 0x002000,
-			Instruction::Add => 0x002001,
-			Instruction::Sub => 0x002002,
-			Instruction::Mul => 0x002003,
-			Instruction::Udiv => 0x002005,
-			Instruction::Sdiv => 0x002006,
-			Instruction::Mod => 0x002008,
-			Instruction::Rem => 0x002009,
-			Instruction::Not => 0x00200b,
-			Instruction::And => 0x00200c,
-			Instruction::Or => 0x00200d,
-			Instruction::Xor => 0x00200e,
-			Instruction::Eq => 0x003000,
-			Instruction::Ne => 0x003001,
-			Instruction::Ult => 0x003002,
-			Instruction::Slt => 0x003003,
-			Instruction::Ugt => 0x003004,
-			Instruction::Sgt => 0x003005,
-			Instruction::Ule => 0x003006,
-			Instruction::Sle => 0x003007,
-			Instruction::Uge => 0x003008,
-			Instruction::Sge => 0x003009,
-			Instruction::Ret => 0x004000,
-			Instruction::Jmp => 0x004001,
-			Instruction::If => 0x004002,
-			Instruction::Call => 0x004003,
+	pub fn opcode(self: &Self) -> u32 {
+		match self.operand {
+			Operand::Embedded(n) => self.op.encode(n),
+			_ => self.op.opcode(),
 		}
 	}
 }
@@ -85,42 +60,175 @@ impl Instruction {
 pub struct Function {
 	pub args: u32,
 	pub ret: u32,
-	pub block: Vec<Instruction>,
+	pub block: Vec<(Instruction, Pos)>,
+}
+
+/// Header fields, which plasma places in the first 0x40 words of the ROM.
+pub enum Directive {
+	Features(u32),
+	Mapping(u32),
+	Fault(Expr),
+	Reset(Expr),
+	Title(String),
+	Developer(String),
+	Publisher(String),
+	/// Raw words written into the header, starting at an index
+	Header(u32, Vec<u32>),
+}
+
+impl Directive {
+	pub fn name(self: &Self) -> &'static str {
+		match self {
+			Directive::Features(_) => "features",
+			Directive::Mapping(_) => "mapping",
+			Directive::Fault(_) => "fault",
+			Directive::Reset(_) => "reset",
+			Directive::Title(_) => "title",
+			Directive::Developer(_) => "developer",
+			Directive::Publisher(_) => "publisher",
+			Directive::Header(..) => "header",
+		}
+	}
+}
+
+/// How the length of a `string` statement is encoded alongside its characters.
+#[derive(Clone, Copy)]
+pub enum StringFormat {
+	Plain,
+	NulTerminated,
+	LengthPrefixed,
 }
 
 pub enum Statement {
-	Function(Function),
-	Skip(u32),
-	SkipTo(u32),
-	Word(Address),
-	Label(String),
-	Def(String, u32),
+	Function(Function, Pos),
+	Skip(u32, Pos),
+	SkipTo(u32, Pos),
+	Word(Expr, Pos),
+	String(String, StringFormat, Pos),
+	Label(String, Pos),
+	Def(String, Pos, Expr),
+	Include(String, Pos),
+	Directive(Directive, Pos),
 }
 
-pub struct Parser<T: Read> {
-	input: T,
-	buffer: Vec<u8>,
+pub struct Parser {
+	source: Rc<Source>,
+	offset: usize,
+	/// Set by a syntax error until the next statement parses, so one mistake
+	/// doesn't cause a cascade of errors for the rest of its block.
+	recovering: bool,
 }
 
-impl<T: Read> Parser<T> {
-	pub fn new(mut input: T) -> Self {
-		let mut buffer = Vec::new();
-		input.read_to_end(&mut buffer).unwrap();
-		Self { input, buffer }
+impl Parser {
+	pub fn new(source: Rc<Source>) -> Self {
+		Self {
+			source,
+			offset: 0,
+			recovering: false,
+		}
+	}
+	fn remaining(self: &Self) -> &[u8] {
+		&self.source.text()[self.offset..]
+	}
+	fn next_line(self: &Self, offset: usize) -> usize {
+		let text = self.source.text();
+		text[offset..]
+			.iter()
+			.position(|c| *c == b'\n')
+			.map_or(text.len(), |p| offset + p + 1)
 	}
 }
 
-impl<'a, T: Read> Iterator for Parser<T> {
-	type Item = Statement;
+impl Iterator for Parser {
+	type Item = Result<Statement, AssembleError>;
 	fn next(self: &mut Self) -> Option<Self::Item> {
-		if self.buffer.len() == 0 {
-			return None;
+		let len = self.source.text().len();
+		loop {
+			if let Ok((rest, _)) = ws(self.remaining()) {
+				self.offset = len - rest.len();
+			}
+			if self.offset == len {
+				return None;
+			}
+			let start = self.offset;
+			match statement(self.remaining()) {
+				Ok((rest, st)) => {
+					self.offset = len - rest.len();
+					self.recovering = false;
+					return Some(Ok(st));
+				}
+				Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+					let offset = len - e.remaining;
+					// Carrying on from the line after the error, rather than
+					// where the statement started, keeps this linear.
+					self.offset = self.next_line(offset);
+					// Lines that don't even start a statement are left over
+					// from the block that already failed
+					if self.recovering && offset == start {
+						continue;
+					}
+					self.recovering = true;
+					return Some(Err(AssembleError::unexpected(
+						Span::new(self.source.clone(), offset),
+						e.expected,
+					)));
+				}
+				Err(nom::Err::Incomplete(_)) => panic!("The grammar only uses complete parsers"),
+			}
 		}
-		let (slice, st) = statement(self.buffer.as_slice()).unwrap();
-		let mut new_buffer = Vec::new();
-		new_buffer.resize(slice.len(), u8::default());
-		new_buffer.copy_from_slice(slice);
-		self.buffer = new_buffer;
-		Some(st)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::tests::assemble;
+
+	/// The line and message of each error
+	fn errors(text: &str) -> Vec<(usize, String)> {
+		assemble(text)
+			.errors()
+			.iter()
+			.map(|e| (e.span.as_ref().unwrap().line(), e.to_string()))
+			.map(|(line, e)| (line, e.lines().next().unwrap().to_string()))
+			.collect()
+	}
+
+	#[test]
+	fn one_error_per_broken_statement() {
+		let text = "\
+.reset main
+:main func 0 0
+	push 1
+	frob
+	drop
+	ret
+:other func 0 0
+	ret
+:broken func 0 0
+	push +
+	ret
+";
+		assert_eq!(
+			errors(text),
+			[
+				(
+					4,
+					"error: unexpected `frob`, expected instruction".to_string()
+				),
+				(10, "error: unexpected `+`, expected number".to_string()),
+			]
+		);
+	}
+
+	#[test]
+	fn stray_tokens_between_statements() {
+		let text = ".reset main\n:main func 0 0 ret\n@\nret\n:next func 0 0 ret\n)\n";
+		assert_eq!(
+			errors(text),
+			[
+				(3, "error: unexpected `@`, expected statement".to_string()),
+				(6, "error: unexpected `)`, expected statement".to_string()),
+			]
+		);
 	}
 }
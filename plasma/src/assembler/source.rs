@@ -0,0 +1,127 @@
+use std::{
+	cmp::Ordering,
+	fmt,
+	fs::File,
+	io::{self, Read},
+	path::{Path, PathBuf},
+	rc::Rc,
+};
+
+pub struct Source {
+	path: PathBuf,
+	text: Vec<u8>,
+}
+impl Source {
+	pub fn load(path: &Path) -> io::Result<Rc<Self>> {
+		let mut text = Vec::new();
+		File::open(path)?.read_to_end(&mut text)?;
+		Ok(Rc::new(Self {
+			path: path.to_path_buf(),
+			text,
+		}))
+	}
+	pub fn path(self: &Self) -> &Path {
+		&self.path
+	}
+	pub fn text(self: &Self) -> &[u8] {
+		&self.text
+	}
+	fn line_start(self: &Self, offset: usize) -> usize {
+		self.text[..offset]
+			.iter()
+			.rposition(|c| *c == b'\n')
+			.map_or(0, |p| p + 1)
+	}
+	fn line_end(self: &Self, offset: usize) -> usize {
+		self.text[offset..]
+			.iter()
+			.position(|c| *c == b'\n' || *c == b'\r')
+			.map_or(self.text.len(), |p| offset + p)
+	}
+}
+
+/// A position as recorded by the grammar: the number of bytes of source left after it.
+#[derive(Clone, Copy)]
+pub struct Pos(pub usize);
+
+/// A position in a source file, used to point diagnostics at the text that caused them.
+#[derive(Clone)]
+pub struct Span {
+	source: Rc<Source>,
+	offset: usize,
+}
+impl Span {
+	pub fn new(source: Rc<Source>, offset: usize) -> Self {
+		Self { source, offset }
+	}
+	pub fn from_pos(source: Rc<Source>, pos: Pos) -> Self {
+		let offset = source.text.len() - pos.0;
+		Self { source, offset }
+	}
+	/// Orders spans by file and then by position within the file.
+	pub fn cmp_position(self: &Self, other: &Self) -> Ordering {
+		self.file()
+			.cmp(other.file())
+			.then(self.offset.cmp(&other.offset))
+	}
+	pub fn file(self: &Self) -> &Path {
+		self.source.path()
+	}
+	/// 1-based line number
+	pub fn line(self: &Self) -> usize {
+		self.source.text[..self.offset]
+			.iter()
+			.filter(|c| **c == b'\n')
+			.count() + 1
+	}
+	/// 1-based column, counted in characters
+	pub fn column(self: &Self) -> usize {
+		let start = self.source.line_start(self.offset);
+		String::from_utf8_lossy(&self.source.text[start..self.offset])
+			.chars()
+			.count() + 1
+	}
+	/// The token starting at this position, empty at the end of the file.
+	pub fn text(self: &Self) -> String {
+		let rest = &self.source.text[self.offset..];
+		let word = |c: &u8| c.is_ascii_alphanumeric() || *c == b'_' || *c == b'.';
+		let len = match rest.first() {
+			Some(c) if word(c) => rest.iter().position(|c| !word(c)).unwrap_or(rest.len()),
+			_ => rest
+				.iter()
+				.position(|c| c.is_ascii_whitespace() || word(c))
+				.unwrap_or(rest.len()),
+		};
+		String::from_utf8_lossy(&rest[..len]).into_owned()
+	}
+	/// The full line of source containing this position.
+	pub fn line_text(self: &Self) -> String {
+		let start = self.source.line_start(self.offset);
+		let end = self.source.line_end(self.offset);
+		String::from_utf8_lossy(&self.source.text[start..end]).into_owned()
+	}
+	/// The line text up to this position with everything but tabs blanked out,
+	/// so that a marker printed after it lines up with the token.
+	fn indent(self: &Self) -> String {
+		let start = self.source.line_start(self.offset);
+		String::from_utf8_lossy(&self.source.text[start..self.offset])
+			.chars()
+			.map(|c| if c == '\t' { '\t' } else { ' ' })
+			.collect()
+	}
+	pub fn caret(self: &Self) -> String {
+		let len = self.text().chars().count().max(1);
+		format!("{}{}", self.indent(), "^".repeat(len))
+	}
+}
+impl fmt::Display for Span {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"{}:{}:{}",
+			self.file().display(),
+			self.line(),
+			self.column()
+		)
+	}
+}
@@ -0,0 +1,323 @@
+use super::{
+	error::AssembleError,
+	parser::{BinOp, Expr},
+	source::{Source, Span},
+};
+use std::{
+	collections::{HashMap, HashSet},
+	rc::Rc,
+};
+
+/// Expressions may produce anything that fits in a 24-bit word, signed or unsigned.
+const MIN_VALUE: i64 = -0x800000;
+const MAX_VALUE: i64 = 0xffffff;
+
+pub enum Value {
+	/// Defined with `:label`
+	Address(u32),
+	/// Defined with `def`, along with the file the expression came from
+	Expr(Expr, Rc<Source>),
+}
+
+struct Symbol {
+	value: Value,
+	definition: Span,
+}
+
+enum State {
+	InProgress,
+	Done(Option<i64>),
+}
+
+pub struct SymbolTable {
+	symbols: HashMap<String, Symbol>,
+	referenced: HashSet<String>,
+	/// Memoised results of evaluating `def` expressions
+	states: HashMap<String, State>,
+}
+impl SymbolTable {
+	pub fn new() -> Self {
+		Self {
+			symbols: HashMap::new(),
+			referenced: HashSet::new(),
+			states: HashMap::new(),
+		}
+	}
+	pub fn define(
+		self: &mut Self,
+		name: String,
+		span: Span,
+		value: Value,
+	) -> Result<(), AssembleError> {
+		if let Some(previous) = self.symbols.get(&name) {
+			return Err(AssembleError::new(
+				span,
+				format!("label `{}` is defined more than once", name),
+			)
+			.with_note(previous.definition.clone(), "previously defined here"));
+		}
+		if let Value::Expr(expr, _) = &value {
+			self.reference(expr);
+		}
+		self.symbols.insert(
+			name,
+			Symbol {
+				value,
+				definition: span,
+			},
+		);
+		Ok(())
+	}
+	/// Records the labels an expression refers to, for unused label warnings.
+	pub fn reference(self: &mut Self, expr: &Expr) {
+		match expr {
+			Expr::Const(_) => {}
+			Expr::Label(name, _) => {
+				self.referenced.insert(name.clone());
+			}
+			Expr::Neg(e, _) => self.reference(e),
+			Expr::Binary(_, l, r, _) => {
+				self.reference(l);
+				self.reference(r);
+			}
+		}
+	}
+	pub fn unused(self: &Self) -> Vec<AssembleError> {
+		let mut warnings: Vec<AssembleError> = self
+			.symbols
+			.iter()
+			.filter(|(name, _)| !self.referenced.contains(*name))
+			.map(|(name, symbol)| {
+				AssembleError::warning(
+					symbol.definition.clone(),
+					format!("label `{}` is never used", name),
+				)
+			})
+			.collect();
+		warnings.sort_by(AssembleError::cmp_position);
+		warnings
+	}
+	/// The value of every label and `def` that has one, in order, along with
+	/// which of the two it is.
+	pub fn values(self: &mut Self) -> Vec<(u32, String, &'static str)> {
+		let names: Vec<(String, Span, &'static str)> = self
+			.symbols
+			.iter()
+			.map(|(name, symbol)| {
+				let kind = match symbol.value {
+					Value::Address(_) => "label",
+					Value::Expr(..) => "def",
+				};
+				(name.clone(), symbol.definition.clone(), kind)
+			})
+			.collect();
+		let mut values: Vec<(u32, String, &'static str)> = names
+			.into_iter()
+			.filter_map(|(name, span, kind)| {
+				let v = self.lookup(&name, span, &mut Vec::new())?;
+				Some((v as u32 & isa::WORD_MASK, name, kind))
+			})
+			.collect();
+		values.sort();
+		values
+	}
+	/// Evaluates every `def`, so that broken ones are reported even if unused.
+	pub fn check_defs(self: &mut Self, errors: &mut Vec<AssembleError>) {
+		let mut names: Vec<(Span, String)> = self
+			.symbols
+			.iter()
+			.filter(|(_, symbol)| matches!(symbol.value, Value::Expr(..)))
+			.map(|(name, symbol)| (symbol.definition.clone(), name.clone()))
+			.collect();
+		names.sort_by(|a, b| a.0.cmp_position(&b.0));
+		for (span, name) in names {
+			self.lookup(&name, span, errors);
+		}
+	}
+	fn lookup(
+		self: &mut Self,
+		name: &str,
+		span: Span,
+		errors: &mut Vec<AssembleError>,
+	) -> Option<i64> {
+		match self.states.get(name) {
+			Some(State::Done(v)) => return *v,
+			Some(State::InProgress) => {
+				errors.push(AssembleError::new(
+					span,
+					format!("`{}` is defined in terms of itself", name),
+				));
+				return None;
+			}
+			None => {}
+		}
+		let (expr, source) = match self.symbols.get(name).map(|s| &s.value) {
+			Some(Value::Address(address)) => return Some(*address as i64),
+			Some(Value::Expr(expr, source)) => (expr.clone(), source.clone()),
+			None => {
+				errors.push(AssembleError::new(
+					span,
+					format!("undefined label `{}`", name),
+				));
+				return None;
+			}
+		};
+		self.states.insert(name.to_string(), State::InProgress);
+		let v = self.evaluate(&expr, &source, errors);
+		self.states.insert(name.to_string(), State::Done(v));
+		v
+	}
+	/// Evaluates an expression, reporting any problems to `errors`. Every
+	/// intermediate result has to fit in 24 bits.
+	pub fn evaluate(
+		self: &mut Self,
+		expr: &Expr,
+		source: &Rc<Source>,
+		errors: &mut Vec<AssembleError>,
+	) -> Option<i64> {
+		let (v, pos) = match expr {
+			Expr::Const(n) => return Some(*n as i64),
+			Expr::Label(name, pos) => {
+				return self.lookup(name, Span::from_pos(source.clone(), *pos), errors)
+			}
+			Expr::Neg(e, pos) => (Some(-self.evaluate(e, source, errors)?), pos),
+			Expr::Binary(op, l, r, pos) => {
+				let l = self.evaluate(l, source, errors);
+				let r = self.evaluate(r, source, errors);
+				let (l, r) = (l?, r?);
+				let v = match op {
+					BinOp::Add => Some(l + r),
+					BinOp::Sub => Some(l - r),
+					BinOp::Mul => Some(l * r),
+					BinOp::Div | BinOp::Mod if r == 0 => {
+						errors.push(AssembleError::new(
+							Span::from_pos(source.clone(), *pos),
+							"division by zero".to_string(),
+						));
+						return None;
+					}
+					BinOp::Div => Some(l / r),
+					BinOp::Mod => Some(l % r),
+					BinOp::And => Some(l & r),
+					BinOp::Or => Some(l | r),
+					BinOp::Xor => Some(l ^ r),
+					BinOp::Shl | BinOp::Shr if !(0..24).contains(&r) => {
+						errors.push(AssembleError::new(
+							Span::from_pos(source.clone(), *pos),
+							format!("shift amount must be 0..23, not {}", r),
+						));
+						return None;
+					}
+					BinOp::Shl => Some(l << r),
+					BinOp::Shr => Some(l >> r),
+				};
+				(v, pos)
+			}
+		};
+		match v {
+			Some(v) if (MIN_VALUE..=MAX_VALUE).contains(&v) => Some(v),
+			_ => {
+				errors.push(AssembleError::new(
+					Span::from_pos(source.clone(), *pos),
+					"expression overflows 24 bits".to_string(),
+				));
+				None
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::tests::{assemble, errors, warnings};
+
+	#[test]
+	fn undefined_labels() {
+		let assembler = assemble(".reset main\n:main func 0 0 push missing jmp\n");
+		assert_eq!(errors(&assembler), ["undefined label `missing`"]);
+		let assembler = assemble(".reset start\n:main func 0 0 ret\n");
+		assert_eq!(errors(&assembler), ["undefined label `start`"]);
+	}
+
+	#[test]
+	fn duplicate_labels() {
+		let assembler = assemble(".reset main\n:main func 0 0 ret\n:main\ndef main 3\n");
+		assert_eq!(
+			errors(&assembler),
+			[
+				"label `main` is defined more than once",
+				"label `main` is defined more than once"
+			]
+		);
+		assert_eq!(assembler.errors()[0].notes.len(), 1);
+	}
+
+	#[test]
+	fn unused_labels() {
+		let assembler = assemble(
+			".reset main\ndef SIZE 3\ndef SPARE SIZE\n:main func 0 0 ret\n:helper func 0 0 ret\n",
+		);
+		assert!(errors(&assembler).is_empty());
+		assert_eq!(
+			warnings(&assembler),
+			[
+				"label `SPARE` is never used",
+				"label `helper` is never used"
+			]
+		);
+	}
+
+	/// The word `expr` assembles to as a `word` statement, or the errors it
+	/// causes.
+	fn evaluate(expr: &str) -> Result<u32, Vec<String>> {
+		let assembler = assemble(&format!(
+			".reset main\n:main func 0 0 ret\ndef ONE 1\nword {} + ONE - ONE\n",
+			expr
+		));
+		match errors(&assembler) {
+			errors if errors.is_empty() => Ok(assembler.words()[0x42]),
+			errors => Err(errors.iter().map(|e| e.to_string()).collect()),
+		}
+	}
+
+	#[test]
+	fn expressions() {
+		assert_eq!(evaluate("1 + 2 * 3"), Ok(7));
+		assert_eq!(evaluate("(1 + 2) * 3"), Ok(9));
+		assert_eq!(evaluate("1 << 4 | 2 & 3 ^ 1"), Ok(0x13));
+		assert_eq!(evaluate("-1"), Ok(0xffffff));
+		assert_eq!(evaluate("-0x800000"), Ok(0x800000));
+		assert_eq!(evaluate("0xffffff >> 20"), Ok(0xf));
+		assert_eq!(evaluate("-7 / 2"), Ok(0xfffffd));
+		assert_eq!(evaluate("-7 % 2"), Ok(0xffffff));
+		assert_eq!(evaluate("main"), Ok(0x40));
+	}
+
+	#[test]
+	fn expression_errors() {
+		let overflow = Err(vec!["expression overflows 24 bits".to_string()]);
+		assert_eq!(evaluate("0xffffff + 1"), overflow);
+		assert_eq!(evaluate("-0x800000 - 1"), overflow);
+		assert_eq!(evaluate("0x800000 * 2 / 2"), overflow);
+		assert_eq!(evaluate("1 << 23 << 1"), overflow);
+		assert_eq!(
+			evaluate("1 / (ONE - 1)"),
+			Err(vec!["division by zero".to_string()])
+		);
+		assert_eq!(evaluate("1 % 0"), Err(vec!["division by zero".to_string()]));
+		assert_eq!(
+			evaluate("1 << 24"),
+			Err(vec!["shift amount must be 0..23, not 24".to_string()])
+		);
+		assert_eq!(
+			evaluate("1 >> -1"),
+			Err(vec!["shift amount must be 0..23, not -1".to_string()])
+		);
+	}
+
+	#[test]
+	fn defs_in_terms_of_themselves() {
+		let assembler = assemble(".reset main\n:main func 0 0 ret\ndef A B\ndef B A + 1\nword A\n");
+		assert_eq!(errors(&assembler), ["`A` is defined in terms of itself"]);
+	}
+}
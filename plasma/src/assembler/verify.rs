@@ -0,0 +1,218 @@
+use super::{
+	error::AssembleError,
+	parser::{Instruction, Operand},
+	source::Span,
+};
+use isa::{header, Op, EMBEDDED_MASK};
+use std::collections::HashMap;
+
+/// A function as it was assembled, kept so that its stack usage can be
+/// checked once every label has been resolved.
+pub struct FunctionRecord {
+	pub address: u32,
+	pub args: u32,
+	pub ret: u32,
+	pub span: Span,
+	/// Each instruction along with the index of its opcode word
+	pub block: Vec<(Instruction, usize, Span)>,
+}
+
+/// Simulates the value stack through every function, the same way the VM's
+/// `StackAccess` would, and reports anything that would fail at run time.
+pub fn verify(functions: &[FunctionRecord], data: &[u32]) -> Vec<AssembleError> {
+	let syscalls = (0..header::SIZE as u32).filter_map(|n| Some((n, isa::syscall::signature(n)?)));
+	let signatures: HashMap<u32, (u32, u32)> = functions
+		.iter()
+		.map(|f| (f.address, (f.args, f.ret)))
+		.chain(syscalls)
+		.collect();
+	functions
+		.iter()
+		.filter_map(|f| verify_function(f, data, &signatures))
+		.collect()
+}
+
+fn verify_function(
+	func: &FunctionRecord,
+	data: &[u32],
+	signatures: &HashMap<u32, (u32, u32)>,
+) -> Option<AssembleError> {
+	if func.args > 0xfff || func.ret > 0xfff {
+		return Some(AssembleError::new(
+			func.span.clone(),
+			"function signatures are limited to 0xfff args and returns".to_string(),
+		));
+	}
+	// The values this function can see, with those known at assemble time
+	let mut stack: Vec<Option<u32>> = vec![None; func.args as usize];
+	for (inst, index, span) in func.block.iter() {
+		let (pops, pushes) = inst.op.stack_effect();
+		if stack.len() < pops {
+			return Some(AssembleError::new(
+				span.clone(),
+				format!(
+					"`{}` needs {} values, but the function only has {} on the stack",
+					inst.op.mnemonic(),
+					pops,
+					stack.len()
+				),
+			));
+		}
+		match (inst.op, &inst.operand) {
+			(Op::Push, _) => stack.push(Some(data[index + 1])),
+			(Op::Peek, Operand::Embedded(n)) => {
+				let n = *n as usize;
+				if n > EMBEDDED_MASK as usize || n >= stack.len() {
+					return Some(AssembleError::new(
+						span.clone(),
+						format!(
+							"`peek {}` is out of range, the function only has {} values on the stack",
+							n,
+							stack.len()
+						),
+					));
+				}
+				stack.push(stack[stack.len() - 1 - n]);
+			}
+			(Op::Ret, _) => {
+				return check_returns(func, inst.op, span, stack.len(), &[], signatures)
+			}
+			(Op::Jmp, _) => {
+				let f = stack.pop().unwrap();
+				return check_returns(func, inst.op, span, stack.len(), &[f], signatures);
+			}
+			(Op::If, _) => {
+				let f1 = stack.pop().unwrap();
+				let f2 = stack.pop().unwrap();
+				stack.pop();
+				return check_returns(func, inst.op, span, stack.len(), &[f2], signatures).or_else(
+					|| check_returns(func, inst.op, span, stack.len(), &[f1], signatures),
+				);
+			}
+			(Op::Call, _) => {
+				let f1 = stack.pop().unwrap();
+				let f2 = stack.pop().unwrap();
+				return check_returns(func, inst.op, span, stack.len(), &[f1, f2], signatures);
+			}
+			_ => {
+				stack.truncate(stack.len() - pops);
+				stack.resize(stack.len() + pushes, None);
+			}
+		}
+	}
+	None
+}
+
+/// Checks that each function the block ends by scheduling has enough args,
+/// and that the values left once they have run match the declared number of
+/// returns. `targets` are in the order they run. Only possible when every
+/// target is a known function.
+fn check_returns(
+	func: &FunctionRecord,
+	end: Op,
+	span: &Span,
+	height: usize,
+	targets: &[Option<u32>],
+	signatures: &HashMap<u32, (u32, u32)>,
+) -> Option<AssembleError> {
+	let mut height = height as isize;
+	for target in targets {
+		let target = (*target)?;
+		let (args, ret) = signatures.get(&target)?;
+		if height < *args as isize {
+			return Some(AssembleError::new(
+				span.clone(),
+				format!(
+					"function at {:#x} takes {} args after `{}`, but only {} values are left on the stack",
+					target,
+					args,
+					end.mnemonic(),
+					height
+				),
+			));
+		}
+		height += *ret as isize - *args as isize;
+	}
+	if height == func.ret as isize {
+		return None;
+	}
+	Some(
+		AssembleError::new(
+			span.clone(),
+			format!(
+				"function returns {} values after `{}`, but is declared to return {}",
+				height,
+				end.mnemonic(),
+				func.ret
+			),
+		)
+		.with_note(func.span.clone(), "function declared here"),
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::tests::{assemble, errors};
+
+	/// Assembled first, so `take2` is at 0x40
+	const HELPERS: &str = "
+		:take2 func 2 0 drop drop ret
+		:give2 func 0 2 push 1 push 2 ret
+	";
+
+	fn check(body: &str) -> Vec<String> {
+		let source = format!(".reset main\n{}\n:main {}", HELPERS, body);
+		errors(&assemble(&source))
+			.into_iter()
+			.map(|e| e.to_string())
+			.collect()
+	}
+
+	#[test]
+	fn call_runs_the_top_function_first() {
+		assert!(check("func 0 0 push take2 push give2 call").is_empty());
+		assert_eq!(
+			check("func 0 0 push give2 push take2 call"),
+			["function at 0x40 takes 2 args after `call`, but only 0 values are left on the stack"]
+		);
+	}
+
+	#[test]
+	fn jumps_need_the_target_args() {
+		assert!(check("func 2 0 push take2 jmp").is_empty());
+		assert_eq!(
+			check("func 1 0 push take2 jmp"),
+			["function at 0x40 takes 2 args after `jmp`, but only 1 values are left on the stack"]
+		);
+		assert_eq!(
+			check("func 1 0 push 0 push take2 push give2 if"),
+			["function at 0x40 takes 2 args after `if`, but only 1 values are left on the stack"]
+		);
+	}
+
+	#[test]
+	fn returns_match_the_signature() {
+		assert!(check("func 0 2 push give2 jmp").is_empty());
+		assert_eq!(
+			check("func 0 1 push give2 jmp"),
+			["function returns 2 values after `jmp`, but is declared to return 1"]
+		);
+		assert_eq!(
+			check("func 1 0 ret"),
+			["function returns 1 values after `ret`, but is declared to return 0"]
+		);
+	}
+
+	#[test]
+	fn underflow() {
+		assert_eq!(
+			check("func 1 0 add ret"),
+			["`add` needs 2 values, but the function only has 1 on the stack"]
+		);
+		assert!(check("func 2 0 peek 1 drop drop drop ret").is_empty());
+		assert_eq!(
+			check("func 2 0 peek 2 ret"),
+			["`peek 2` is out of range, the function only has 2 values on the stack"]
+		);
+	}
+}
@@ -0,0 +1,290 @@
+use isa::{header, Op, OperandKind};
+use std::{
+	collections::{BTreeMap, HashSet},
+	fmt::Write,
+};
+
+struct Function {
+	args: u32,
+	ret: u32,
+	/// Each instruction along with its operand, if it has one
+	block: Vec<(Op, Option<u32>)>,
+	/// Functions this one ends by scheduling, where they are known
+	targets: Vec<u32>,
+	/// Address just past the end of the function
+	end: usize,
+}
+
+/// Decodes the function starting at `address`, or returns `None` if the
+/// words there don't make up a complete function.
+fn decode_function(rom: &[u32], address: usize) -> Option<Function> {
+	let sig = *rom.get(address)?;
+	let (args, ret) = isa::decompose_signature(sig);
+	let mut block = Vec::new();
+	let mut targets = Vec::new();
+	// Values pushed by this function that are known before it runs
+	let mut stack: Vec<Option<u32>> = vec![None; args as usize];
+	let mut at = address + 1;
+	loop {
+		let word = *rom.get(at)?;
+		let op = Op::decode(word)?;
+		at += 1;
+		let operand = match op.operand() {
+			OperandKind::None => None,
+			OperandKind::Word => {
+				at += 1;
+				Some(*rom.get(at - 1)?)
+			}
+			OperandKind::Embedded => Some(isa::embedded(word)),
+		};
+		match op {
+			Op::Push => stack.push(operand),
+			Op::Peek => {
+				let v = stack
+					.len()
+					.checked_sub(operand? as usize + 1)
+					.and_then(|i| stack[i]);
+				stack.push(v);
+			}
+			Op::Ret => {}
+			Op::Jmp | Op::Call | Op::If => {
+				let count = if let Op::Jmp = op { 1 } else { 2 };
+				for _ in 0..count {
+					if let Some(Some(target)) = stack.pop() {
+						targets.push(target);
+					}
+				}
+			}
+			_ => {
+				let (pops, pushes) = op.stack_effect();
+				stack.truncate(stack.len().saturating_sub(pops));
+				stack.resize(stack.len() + pushes, None);
+			}
+		}
+		block.push((op, operand));
+		if op.ends_function() {
+			return Some(Function {
+				args,
+				ret,
+				block,
+				targets,
+				end: at,
+			});
+		}
+	}
+}
+
+/// Finds every function reachable from the header's vectors by following the
+/// constant function pointers pushed before `jmp`, `if` and `call`.
+fn discover(rom: &[u32], vectors: &[u32]) -> BTreeMap<usize, Function> {
+	let mut functions = BTreeMap::new();
+	let mut visited = HashSet::new();
+	let mut pending = vectors.to_vec();
+	while let Some(address) = pending.pop() {
+		let address = address as usize;
+		if address < header::SIZE || !visited.insert(address) {
+			continue;
+		}
+		if let Some(func) = decode_function(rom, address) {
+			pending.extend(func.targets.iter());
+			functions.insert(address, func);
+		}
+	}
+	// A pointer into the middle of another function can't be labelled, so
+	// treat it as an ordinary number.
+	let mut end = 0;
+	let mut overlapping = Vec::new();
+	for (address, func) in functions.iter() {
+		if *address < end {
+			overlapping.push(*address);
+		} else {
+			end = func.end;
+		}
+	}
+	for address in overlapping {
+		functions.remove(&address);
+	}
+	functions
+}
+
+fn label(address: u32) -> String {
+	format!("func_{:06x}", address)
+}
+
+fn escape(text: &str) -> String {
+	text.chars()
+		.map(|c| match c {
+			'"' => "\\\"".to_string(),
+			'\\' => "\\\\".to_string(),
+			'\n' => "\\n".to_string(),
+			'\r' => "\\r".to_string(),
+			'\t' => "\\t".to_string(),
+			c if c.is_control() => format!("\\u{{{:x}}}", c as u32),
+			c => c.to_string(),
+		})
+		.collect()
+}
+
+/// The words of a header string up to the last character that isn't NUL, or
+/// `None` if they aren't all characters and can't be written as a string.
+fn header_string(raw: &[u32]) -> Option<String> {
+	let len = raw.iter().rposition(|c| *c != 0).map_or(0, |i| i + 1);
+	raw[..len].iter().map(|c| std::char::from_u32(*c)).collect()
+}
+
+/// Writes `words` as a `.header` directive starting at `start`, leaving off
+/// trailing zeros, which plasma fills in anyway.
+fn raw_header(out: &mut String, start: usize, words: &[u32]) {
+	let len = match words.iter().rposition(|w| *w != 0) {
+		Some(i) => i + 1,
+		None => return,
+	};
+	let words: Vec<String> = words[..len].iter().map(|w| format!("{:#x}", w)).collect();
+	writeln!(out, ".header {:#x} {}", start, words.join(" ")).unwrap();
+}
+
+/// Turns a ROM back into plasma source.
+pub fn disassemble(rom: &[u32]) -> Result<String, String> {
+	if rom.len() < header::SIZE || rom[0x0] != header::MAGIC {
+		return Err("not a PLT ROM, the magic number is missing".to_string());
+	}
+	let reset = rom[header::RESET];
+	let fault = rom[header::FAULT];
+	let functions = discover(rom, &[reset, fault]);
+	let name = |v: u32| {
+		if functions.contains_key(&(v as usize)) {
+			label(v)
+		} else {
+			format!("{:#x}", v)
+		}
+	};
+
+	let mut out = String::new();
+	if rom[header::FEATURES] != 0 {
+		writeln!(out, ".features {:#x}", rom[header::FEATURES]).unwrap();
+	}
+	if rom[header::MAPPING] != 0 {
+		writeln!(out, ".mapping {:#x}", rom[header::MAPPING]).unwrap();
+	}
+	if fault != 0 {
+		writeln!(out, ".fault {}", name(fault)).unwrap();
+	}
+	writeln!(out, ".reset {}", name(reset)).unwrap();
+	let strings = [
+		("title", header::TITLE),
+		("developer", header::DEVELOPER),
+		("publisher", header::PUBLISHER),
+	];
+	for (directive, start) in strings.iter() {
+		let raw = &rom[*start..*start + header::STRING_LEN];
+		match header_string(raw) {
+			Some(text) if text.is_empty() => {}
+			Some(text) => writeln!(out, ".{} \"{}\"", directive, escape(&text)).unwrap(),
+			None => raw_header(&mut out, *start, raw),
+		}
+	}
+	// Words plasma has no directive for
+	raw_header(
+		&mut out,
+		header::MAPPING + 1,
+		&rom[header::MAPPING + 1..header::FAULT],
+	);
+
+	let mut address = header::SIZE;
+	while address < rom.len() {
+		match functions.get(&address) {
+			Some(func) => {
+				writeln!(out, "\n:{}", label(address as u32)).unwrap();
+				writeln!(out, "func {} {}", func.args, func.ret).unwrap();
+				for (op, operand) in func.block.iter() {
+					match (op.operand(), operand) {
+						(OperandKind::Word, Some(v)) => {
+							writeln!(out, "\t{} {}", op.mnemonic(), name(*v))
+						}
+						(_, Some(n)) => writeln!(out, "\t{} {}", op.mnemonic(), n),
+						(_, None) => writeln!(out, "\t{}", op.mnemonic()),
+					}
+					.unwrap();
+				}
+				address = func.end;
+			}
+			None => {
+				writeln!(out, "word {:#08x}", rom[address]).unwrap();
+				address += 1;
+			}
+		}
+	}
+	Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::assembler::tests::{assemble, errors};
+
+	/// Disassembles `rom` and assembles the result again.
+	fn round_trip(rom: &[u32]) -> Vec<u32> {
+		let source = disassemble(rom).unwrap();
+		let assembler = assemble(&source);
+		assert!(errors(&assembler).is_empty(), "{}", source);
+		assembler.words().to_vec()
+	}
+
+	#[test]
+	fn assembled_roms_round_trip() {
+		let assembler = assemble(
+			"\
+.mapping 1
+.reset main
+.fault handler
+.title \"Round \\\"trip\\\"\"
+.developer \"\\u{e9}\\t\"
+.header 0x3 1 0 2
+:main func 0 0
+	push 'a'
+	push other
+	push 1
+	call
+:other func 0 0
+	push data
+	load
+	peek 0
+	drop
+	drop
+	ret
+:handler func 3 0 drop drop drop ret
+:data word data
+stringz \"text\"
+pstring \"\"
+skip 3
+",
+		);
+		assert!(errors(&assembler).is_empty());
+		let rom = assembler.words();
+		let source = disassemble(rom).unwrap();
+		assert!(source.contains(".fault func_000050\n"));
+		assert!(source.contains("\tpush func_000048\n"));
+		assert_eq!(round_trip(rom), rom);
+	}
+
+	#[test]
+	fn raw_words_round_trip() {
+		let mut rom = vec![0; header::SIZE];
+		rom[0] = header::MAGIC;
+		rom[header::RESET] = 0x123456;
+		// A title with a character after its NUL, and one that isn't a character
+		rom[header::TITLE] = 'a' as u32;
+		rom[header::TITLE + 2] = 'b' as u32;
+		rom[header::DEVELOPER] = 0xd800;
+		rom[header::FAULT - 1] = 0xffffff;
+		// Words that look like the start of a function, but never end
+		rom.extend(&[isa::signature(0, 0), Op::Push.opcode(), 0x40, 0xffffff]);
+		assert_eq!(round_trip(&rom), rom);
+	}
+
+	#[test]
+	fn not_a_rom() {
+		assert!(disassemble(&[header::MAGIC]).is_err());
+		assert!(disassemble(&[0; header::SIZE]).is_err());
+	}
+}
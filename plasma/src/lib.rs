@@ -0,0 +1,10 @@
+#![allow(clippy::needless_arbitrary_self_type)]
+
+//! The Pluto assembler, exposed as a library so other tools can assemble or
+//! disassemble ROMs without going through the command line.
+
+pub mod assembler;
+pub mod disassembler;
+
+pub use assembler::{AssembleError, Assembler};
+pub use disassembler::disassemble;
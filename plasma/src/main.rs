@@ -1,33 +1,129 @@
-mod assembler;
-
-use assembler::Assembler;
-use std::{fs::File, path::PathBuf};
+use plasma::Assembler;
+use std::{
+	fs::{self, File},
+	io::{self, BufWriter, Write},
+	path::{Path, PathBuf},
+	process,
+};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
 struct Opt {
-	source: PathBuf,
+	/// Source files, assembled in order into a single ROM, or the ROM to disassemble
+	#[structopt(parse(from_os_str), required = true)]
+	sources: Vec<PathBuf>,
+	/// Turn a PLT ROM back into source, written to stdout unless an output is given
+	#[structopt(short, long)]
+	disassemble: bool,
 	#[structopt(short, long, parse(from_os_str))]
 	output: Option<PathBuf>,
+	/// Write every label and def with its value to this file
+	#[structopt(long, parse(from_os_str))]
+	symbols: Option<PathBuf>,
+	/// Write a listing of each word's address and value beside its source line to this file
+	#[structopt(long, parse(from_os_str))]
+	listing: Option<PathBuf>,
 }
 
 fn main() {
 	let opt = Opt::from_args();
 
+	if opt.disassemble {
+		disassemble(opt);
+	} else {
+		assemble(opt);
+	}
+}
+
+fn disassemble(opt: Opt) {
+	if opt.sources.len() != 1 {
+		eprintln!("error: only one ROM can be disassembled at a time");
+		process::exit(1);
+	}
+	let rom = match fs::read(&opt.sources[0]) {
+		Ok(rom) => rom,
+		Err(e) => {
+			eprintln!("error: {}: {}", opt.sources[0].display(), e);
+			process::exit(1);
+		}
+	};
+	if rom.len() % 3 != 0 {
+		eprintln!(
+			"warning: {}: the last {} bytes don't make up a whole word, so they are left out",
+			opt.sources[0].display(),
+			rom.len() % 3
+		);
+	}
+	let source = match plasma::disassemble(&isa::words_from_bytes(&rom)) {
+		Ok(source) => source,
+		Err(e) => {
+			eprintln!("error: {}: {}", opt.sources[0].display(), e);
+			process::exit(1);
+		}
+	};
+	let (output, result) = match &opt.output {
+		Some(o) => (o.display().to_string(), fs::write(o, source)),
+		None => (
+			"stdout".to_string(),
+			io::stdout().write_all(source.as_bytes()),
+		),
+	};
+	if let Err(e) = result {
+		eprintln!("error: {}: {}", output, e);
+		process::exit(1);
+	}
+}
+
+fn assemble(opt: Opt) {
 	let mut ass = Assembler::new();
 
-	ass.load_file(&opt.source);
-
-	ass.write(
-		File::create(match opt.output {
-			Some(o) => o,
-			None => {
-				let mut p = opt.source.clone();
-				p.set_extension("plt");
-				p
-			}
-		})
-		.unwrap(),
-	)
-	.unwrap();
+	for source in opt.sources.iter() {
+		if let Err(e) = ass.load_file(source) {
+			eprintln!("error: {}: {}", source.display(), e);
+			process::exit(1);
+		}
+	}
+	ass.finish();
+	for w in ass.warnings() {
+		eprintln!("{}\n", w);
+	}
+	if !ass.errors().is_empty() {
+		for e in ass.errors() {
+			eprintln!("{}\n", e);
+		}
+		match ass.errors().len() {
+			1 => eprintln!("error: aborting due to previous error"),
+			n => eprintln!("error: aborting due to {} previous errors", n),
+		}
+		process::exit(1);
+	}
+
+	if let Some(path) = opt.symbols {
+		write_file(&path, |out| ass.write_symbols(out));
+	}
+	if let Some(path) = opt.listing {
+		write_file(&path, |out| ass.write_listing(out));
+	}
+	let output = match opt.output {
+		Some(o) => o,
+		None => {
+			let mut p = opt.sources[0].clone();
+			p.set_extension("plt");
+			p
+		}
+	};
+	write_file(&output, |out| ass.write(out));
+}
+
+/// Creates the file at `path` and writes it with `write`, exiting if either fails.
+fn write_file(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) {
+	let result = File::create(path).and_then(|file| {
+		let mut out = BufWriter::new(file);
+		write(&mut out)?;
+		out.flush()
+	});
+	if let Err(e) = result {
+		eprintln!("error: {}: {}", path.display(), e);
+		process::exit(1);
+	}
 }
@@ -0,0 +1,195 @@
+#![allow(clippy::needless_arbitrary_self_type)]
+
+//! Times the VM on an instruction heavy loop that touches ROM, RAM and the I/O
+//! window, to measure the overhead of the interpreter and memory map. Memory
+//! accesses are also timed on their own, against the block scan the page table
+//! replaced. Run with `cargo run --release --example bench`.
+
+use isa::{header, mapping, signature, Op};
+use pluto::{PlutoVM, Status};
+use std::{cell::RefCell, hint::black_box, rc::Rc, time::Instant};
+
+const ITERATIONS: u32 = 1_000_000;
+const RAM: u32 = 0x800000;
+const IO: u32 = 0xf00000;
+/// In the I/O window, but past every device
+const IO_EMPTY: u32 = 0xf80000;
+
+/// The body of the loop, which leaves the stack as it found it.
+fn body() -> Vec<u32> {
+	let mut words = vec![
+		Op::Peek.encode(0),
+		Op::Push.opcode(),
+		RAM,
+		Op::Stor.opcode(),
+	];
+	for _ in 0..10 {
+		words.extend(&[
+			Op::Peek.encode(0),
+			Op::Push.opcode(),
+			RAM,
+			Op::Load.opcode(),
+			Op::Add.opcode(),
+			Op::Push.opcode(),
+			IO,
+			Op::Load.opcode(),
+			Op::Add.opcode(),
+			Op::Drop.opcode(),
+		]);
+	}
+	words
+}
+
+fn rom() -> Vec<u8> {
+	let mut words = vec![0; header::SIZE];
+	words[0x0] = header::MAGIC;
+	words[header::MAPPING] = 1;
+	words[header::RESET] = 0x40;
+	let body = body();
+	let main = words.len() as u32;
+	let count = main + 6;
+	let done = count + 4 + body.len() as u32 + 6;
+	words.extend(&[
+		signature(0, 0),
+		Op::Push.opcode(),
+		ITERATIONS,
+		Op::Push.opcode(),
+		count,
+		Op::Jmp.opcode(),
+	]);
+	words.extend(&[signature(1, 0), Op::Push.opcode(), 1, Op::Sub.opcode()]);
+	words.extend(body);
+	words.extend(&[
+		Op::Peek.encode(0),
+		Op::Push.opcode(),
+		done,
+		Op::Push.opcode(),
+		count,
+		Op::If.opcode(),
+	]);
+	words.extend(&[signature(1, 0), Op::Drop.opcode(), Op::Ret.opcode()]);
+	isa::bytes_from_words(&words)
+}
+
+struct Block {
+	contents: Vec<u32>,
+	offset: u32,
+	size: u32,
+	writeable: bool,
+}
+impl Block {
+	fn check(self: &Self, address: u32, write: bool) -> bool {
+		address >= self.offset && address - self.offset < self.size && (!write || self.writeable)
+	}
+}
+
+/// The memory map from before the page table, which checked every block in
+/// turn. Only here as a baseline, with the mapping's devices added as blocks
+/// checked ahead of the I/O window they cover.
+struct BlockScan {
+	blocks: Rc<RefCell<Vec<Block>>>,
+}
+impl BlockScan {
+	fn new(rom: Vec<u32>) -> Self {
+		let layout = mapping::layout(1).unwrap();
+		let (ram, io) = (layout.ram.unwrap(), layout.io.unwrap());
+		let block = |offset, size, contents, writeable| Block {
+			contents,
+			offset,
+			size,
+			writeable,
+		};
+		let mut blocks: Vec<Block> = pluto::devices::for_mapping(1)
+			.into_iter()
+			.rev()
+			.map(|(start, device)| block(start, device.size(), Vec::new(), true))
+			.collect();
+		blocks.extend(vec![
+			block(layout.rom.start, rom.len() as u32, rom, false),
+			block(ram.start, ram.size, vec![0; ram.size as usize], true),
+			block(io.start, io.size, Vec::new(), true),
+		]);
+		Self {
+			blocks: Rc::new(RefCell::new(blocks)),
+		}
+	}
+	#[inline(never)]
+	fn read(self: &Self, address: u32) -> Option<u32> {
+		self.blocks
+			.borrow()
+			.iter()
+			.find(|b| b.check(address, false))
+			.map(|b| {
+				b.contents
+					.get((address - b.offset) as usize)
+					.copied()
+					.unwrap_or(0)
+			})
+	}
+	#[inline(never)]
+	fn write(self: &Self, address: u32, value: u32) -> Option<()> {
+		self.blocks
+			.borrow_mut()
+			.iter_mut()
+			.find(|b| b.check(address, true))
+			.map(|b| {
+				if let Some(word) = b.contents.get_mut((address - b.offset) as usize) {
+					*word = value & 0xffffff;
+				}
+			})
+	}
+}
+
+/// Times `ITERATIONS` rounds of a ROM read, a RAM write and read, and an I/O
+/// read, returning the number of accesses per second.
+fn time_accesses(
+	mut read: impl FnMut(u32) -> Option<u32>,
+	mut write: impl FnMut(u32, u32) -> Option<()>,
+) -> f64 {
+	let start = Instant::now();
+	for i in 0..ITERATIONS {
+		let rom = read(header::SIZE as u32 + (i & 0x1f)).unwrap();
+		write(RAM + (i & 0xff), rom).unwrap();
+		black_box(read(RAM + (i & 0xff)).unwrap());
+		black_box(read(IO_EMPTY).unwrap());
+	}
+	(ITERATIONS * 4) as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+	let mut vm = PlutoVM::new(rom()).unwrap();
+
+	let mut memory = vm.memory().clone();
+	let reader = memory.clone();
+	let page_table = time_accesses(
+		|address| reader.read(address).ok(),
+		|address, value| memory.write(address, value).ok(),
+	);
+	let scan = BlockScan::new(isa::words_from_bytes(&rom()));
+	let block_scan = time_accesses(
+		|address| scan.read(address),
+		|address, value| scan.write(address, value),
+	);
+	println!(
+		"memory: page table {:.1} million accesses per second, block scan {:.1} million",
+		page_table / 1e6,
+		block_scan / 1e6
+	);
+
+	let mut ticks: u64 = 0;
+	let start = Instant::now();
+	loop {
+		match vm.tick() {
+			Ok(Status::Running) => ticks += 1,
+			Ok(_) => break,
+			Err(fault) => panic!("{}", fault),
+		}
+	}
+	let elapsed = start.elapsed().as_secs_f64();
+	println!(
+		"vm: {} ticks in {:.3}s, {:.1} million per second",
+		ticks,
+		elapsed,
+		ticks as f64 / elapsed / 1e6
+	);
+}
@@ -0,0 +1,277 @@
+use crate::{
+	symbols::{parse_number, Symbols},
+	Runtime,
+};
+use isa::{Op, OperandKind};
+use pluto::{MemoryAccessor, Status};
+use std::{
+	collections::BTreeSet,
+	io::{self, prelude::*},
+	rc::Rc,
+};
+
+const HELP: &str = "\
+step [n]            run n instructions, 1 if not given. Starting a function
+                    counts as a step of its own (s)
+next                finish the running function, or between functions, run the
+                    next one along with everything it schedules (n)
+continue            run until a breakpoint or the end of the program (c)
+break [location]    stop before the instruction or function at location, or
+                    list the breakpoints (b)
+delete <location>   remove a breakpoint (d)
+where               show what runs next (w)
+stack               show the value stack, top first
+funcs               show the functions waiting to run, next first
+x <address> [n]     dump n words of memory, 8 if not given
+quit                stop the program (q)
+
+A location is an address or a name from the symbol file. An empty line
+repeats the last command.";
+
+pub struct Debugger {
+	runtime: Runtime,
+	symbols: Rc<Symbols>,
+	breakpoints: BTreeSet<u32>,
+	/// The exit code, once the program has stopped
+	stopped: Option<i32>,
+}
+impl Debugger {
+	pub fn new(runtime: Runtime, symbols: Rc<Symbols>) -> Self {
+		Self {
+			runtime,
+			symbols,
+			breakpoints: BTreeSet::new(),
+			stopped: None,
+		}
+	}
+	pub fn run(mut self: Self) -> ! {
+		println!("Type `help` for a list of commands.");
+		self.show_position();
+		let stdin = io::stdin();
+		let mut last = String::new();
+		loop {
+			print!("(pluto) ");
+			io::stdout().flush().unwrap();
+			let mut line = String::new();
+			if stdin.lock().read_line(&mut line).unwrap() == 0 {
+				break;
+			}
+			let line = match line.trim() {
+				"" => last.clone(),
+				line => line.to_string(),
+			};
+			let words: Vec<&str> = line.split_whitespace().collect();
+			match words.as_slice() {
+				[] => {}
+				["q"] | ["quit"] => break,
+				["h"] | ["help"] => println!("{}", HELP),
+				["s"] | ["step"] => self.step(1),
+				["s", n] | ["step", n] => match parse_number(n) {
+					Some(n) => self.step(n),
+					None => println!("`{}` isn't a number", n),
+				},
+				["n"] | ["next"] => self.next(),
+				["c"] | ["continue"] => self.resume(|_| false),
+				["b"] | ["break"] => {
+					for address in self.breakpoints.iter() {
+						println!("{}", self.symbols.describe(*address));
+					}
+				}
+				["b", location] | ["break", location] => {
+					if let Some(address) = self.location(location) {
+						self.breakpoints.insert(address);
+					}
+				}
+				["d", location] | ["delete", location] => {
+					if let Some(address) = self.location(location) {
+						if !self.breakpoints.remove(&address) {
+							println!("no breakpoint at {}", self.symbols.describe(address));
+						}
+					}
+				}
+				["w"] | ["where"] => self.show_position(),
+				["stack"] => self.show_stack(),
+				["funcs"] => self.show_funcs(),
+				["x", address] => self.dump(address, 8),
+				["x", address, n] => match parse_number(n) {
+					Some(n) => self.dump(address, n),
+					None => println!("`{}` isn't a number", n),
+				},
+				_ => println!("unknown command `{}`, try `help`", line),
+			}
+			last = line;
+		}
+		self.runtime.exit(self.stopped.unwrap_or(0));
+	}
+
+	/// Runs one instruction, or between functions starts the next one
+	/// without running any of it, returning false once the program has
+	/// stopped.
+	fn tick(self: &mut Self) -> bool {
+		if let Some(code) = self.stopped {
+			println!("the program has stopped, with exit code {}", code);
+			return false;
+		}
+		let result = match self.runtime.vm.position() {
+			Some(_) => self.runtime.tick(),
+			None => self.runtime.dispatch(),
+		};
+		let code = match result {
+			Ok(Status::Running) => return true,
+			Ok(Status::Finished) => {
+				println!("the program finished");
+				0
+			}
+			Ok(Status::Exit(code)) => {
+				println!("the program exited with code {}", code);
+				code as i32
+			}
+			Ok(Status::Limit(limit)) => {
+				println!("the program reached the {}", limit);
+				crate::LIMIT_EXIT
+			}
+			Err(fault) => {
+				println!("fault: {}", fault);
+				1
+			}
+		};
+		self.stopped = Some(code);
+		false
+	}
+	fn step(self: &mut Self, count: u32) {
+		for _ in 0..count {
+			if !self.tick() {
+				return;
+			}
+		}
+		self.show_position();
+	}
+	fn next(self: &mut Self) {
+		let vm = &self.runtime.vm;
+		if vm.position().is_some() {
+			return self.resume(|vm| vm.position().is_none());
+		}
+		// Everything the next function schedules has run once the functions
+		// that were waiting below it are all that's left
+		let waiting = vm.function_stack().len().saturating_sub(1);
+		self.resume(|vm| vm.position().is_none() && vm.function_stack().len() <= waiting);
+	}
+	/// Runs until `done`, a breakpoint, or the end of the program.
+	fn resume(self: &mut Self, done: impl Fn(&pluto::PlutoVM) -> bool) {
+		loop {
+			if !self.tick() {
+				return;
+			}
+			if let Some(address) = self.next_address() {
+				if self.breakpoints.contains(&address) {
+					println!("breakpoint at {}", self.symbols.describe(address));
+					break;
+				}
+			}
+			if done(&self.runtime.vm) {
+				break;
+			}
+		}
+		self.show_position();
+	}
+
+	/// The instruction, or function, that runs next.
+	fn next_address(self: &Self) -> Option<u32> {
+		let vm = &self.runtime.vm;
+		match vm.position() {
+			Some((_, prg_ptr)) => Some(prg_ptr),
+			None => vm.function_stack().last().copied(),
+		}
+	}
+	fn show_position(self: &Self) {
+		if self.stopped.is_some() {
+			return;
+		}
+		let vm = &self.runtime.vm;
+		match vm.position() {
+			Some((func_ptr, prg_ptr)) => {
+				println!(
+					"in {}, at {}",
+					self.symbols.describe(func_ptr),
+					self.symbols.describe(prg_ptr)
+				);
+				println!(
+					"    {}",
+					instruction(self.runtime.vm.memory(), &self.symbols, prg_ptr)
+				);
+			}
+			None => match vm.function_stack().last() {
+				Some(func_ptr) => println!("about to run {}", self.symbols.describe(*func_ptr)),
+				None => println!("no functions left to run"),
+			},
+		}
+	}
+	fn show_stack(self: &Self) {
+		let (values, disallowed) = self.runtime.vm.value_stack();
+		for (i, value) in values.iter().enumerate().rev() {
+			if i + 1 == disallowed {
+				println!("    -- not accessible to the running function --");
+			}
+			println!(
+				"{:4}: {:#08x} {}",
+				values.len() - 1 - i,
+				value,
+				isa::sign_extend(*value)
+			);
+		}
+	}
+	fn show_funcs(self: &Self) {
+		for func_ptr in self.runtime.vm.function_stack().iter().rev() {
+			println!("{}", self.symbols.describe(*func_ptr));
+		}
+	}
+	fn dump(self: &Self, location: &str, count: u32) {
+		let start = match self.location(location) {
+			Some(start) => start,
+			None => return,
+		};
+		let memory = self.runtime.vm.memory();
+		for row in (0..count).step_by(8) {
+			let address = start.wrapping_add(row) & isa::WORD_MASK;
+			let words: Vec<String> = (row..count.min(row + 8))
+				.map(
+					|i| match memory.read(start.wrapping_add(i) & isa::WORD_MASK) {
+						Ok(word) => format!("{:06x}", word),
+						Err(_) => "??????".to_string(),
+					},
+				)
+				.collect();
+			println!("{:#08x}: {}", address, words.join(" "));
+		}
+	}
+	fn location(self: &Self, location: &str) -> Option<u32> {
+		let address = self.symbols.resolve(location);
+		if address.is_none() {
+			println!("`{}` isn't an address or a known symbol", location);
+		}
+		address
+	}
+}
+
+/// The instruction at `address`, as it would be written in plasma.
+pub fn instruction(memory: &MemoryAccessor, symbols: &Symbols, address: u32) -> String {
+	let word = match memory.read(address) {
+		Ok(word) => word,
+		Err(fault) => return format!("<{}>", fault),
+	};
+	let op = match Op::decode(word) {
+		Some(op) => op,
+		None => return format!("word {:#08x}", word),
+	};
+	match op.operand() {
+		OperandKind::None => op.mnemonic().to_string(),
+		OperandKind::Embedded => format!("{} {}", op.mnemonic(), isa::embedded(word)),
+		OperandKind::Word => match memory.read(address + 1) {
+			Ok(v) => match symbols.name(v) {
+				Some(name) => format!("{} {}", op.mnemonic(), name),
+				None => format!("{} {:#x}", op.mnemonic(), v),
+			},
+			Err(fault) => format!("{} <{}>", op.mnemonic(), fault),
+		},
+	}
+}
@@ -0,0 +1,103 @@
+use crate::vm::Device;
+use std::{cell::Cell, rc::Rc};
+
+/// A single word holding which buttons are held down, one bit per button.
+/// The host sets the buttons through the shared cell the controller was made
+/// with.
+#[derive(Default)]
+pub struct Controller {
+	buttons: Rc<Cell<u32>>,
+}
+impl Controller {
+	pub const ADDRESS: u32 = 0xf15000;
+	pub const BUTTONS: [(&'static str, u32); 8] = [
+		("up", 0x01),
+		("down", 0x02),
+		("left", 0x04),
+		("right", 0x08),
+		("a", 0x10),
+		("b", 0x20),
+		("start", 0x40),
+		("select", 0x80),
+	];
+
+	pub fn new(buttons: Rc<Cell<u32>>) -> Self {
+		Self { buttons }
+	}
+}
+impl Device for Controller {
+	fn size(self: &Self) -> u32 {
+		1
+	}
+	fn read(self: &mut Self, _offset: u32) -> u32 {
+		self.buttons.get()
+	}
+	fn write(self: &mut Self, _offset: u32, _value: u32) {}
+}
+
+/// Controller input recorded against frame numbers, so a play session can be
+/// replayed exactly.
+///
+/// Each line is a frame number followed by the buttons held from that frame
+/// on, either by name or as a number. A line with no buttons releases them
+/// all. Blank lines and anything after a `#` are ignored:
+///
+/// ```text
+/// 0            # nothing held at the start
+/// 60 right     # walk right for a second
+/// 90 right a   # then jump
+/// 95
+/// ```
+pub struct InputScript {
+	/// Frame numbers along with the buttons held from them on, in order
+	changes: Vec<(u32, u32)>,
+}
+impl InputScript {
+	pub fn parse(text: &str) -> Result<Self, String> {
+		let mut changes: Vec<(u32, u32)> = Vec::new();
+		for (line_no, line) in text.lines().enumerate() {
+			let line = line.split('#').next().unwrap_or("");
+			let mut words = line.split_whitespace();
+			let frame = match words.next() {
+				Some(word) => parse_number(word)
+					.ok_or_else(|| format!("line {}: bad frame number `{}`", line_no + 1, word))?,
+				None => continue,
+			};
+			if let Some((last, _)) = changes.last() {
+				if frame <= *last {
+					return Err(format!(
+						"line {}: frame {} comes after frame {}",
+						line_no + 1,
+						frame,
+						last
+					));
+				}
+			}
+			let mut buttons = 0;
+			for word in words {
+				buttons |= Controller::BUTTONS
+					.iter()
+					.find(|(name, _)| name.eq_ignore_ascii_case(word))
+					.map(|(_, bit)| *bit)
+					.or_else(|| parse_number(word))
+					.ok_or_else(|| format!("line {}: unknown button `{}`", line_no + 1, word))?;
+			}
+			changes.push((frame, buttons & isa::WORD_MASK));
+		}
+		Ok(Self { changes })
+	}
+	/// The buttons held during `frame`.
+	pub fn buttons_at(self: &Self, frame: u32) -> u32 {
+		match self.changes.partition_point(|(start, _)| *start <= frame) {
+			0 => 0,
+			i => self.changes[i - 1].1,
+		}
+	}
+}
+
+fn parse_number(word: &str) -> Option<u32> {
+	match word.strip_prefix("0x") {
+		Some(hex) => u32::from_str_radix(hex, 16).ok(),
+		None => word.parse().ok(),
+	}
+}
@@ -0,0 +1,56 @@
+use crate::vm::Device;
+
+pub type VsyncHandler = Box<dyn FnMut(u32, &[u32])>;
+
+/// A `WIDTH` by `HEIGHT` screen, one 0xRRGGBB word per pixel in rows from the
+/// top left. The first page holds the vsync register: writing to it finishes a
+/// frame, and reading it returns how many frames have been finished. The
+/// pixels start on the next page.
+pub struct FrameBuffer {
+	pixels: Vec<u32>,
+	frame: u32,
+	on_vsync: VsyncHandler,
+}
+impl FrameBuffer {
+	pub const ADDRESS: u32 = 0xf01000;
+	pub const WIDTH: u32 = 320;
+	pub const HEIGHT: u32 = 240;
+	const PIXELS: u32 = 0x1000;
+
+	/// `on_vsync` is given the number of the finished frame, counting from 0,
+	/// and its pixels.
+	pub fn new(on_vsync: impl FnMut(u32, &[u32]) + 'static) -> Self {
+		Self {
+			pixels: vec![0; (Self::WIDTH * Self::HEIGHT) as usize],
+			frame: 0,
+			on_vsync: Box::new(on_vsync),
+		}
+	}
+}
+impl Default for FrameBuffer {
+	fn default() -> Self {
+		Self::new(|_, _| {})
+	}
+}
+impl Device for FrameBuffer {
+	fn size(self: &Self) -> u32 {
+		Self::PIXELS + Self::WIDTH * Self::HEIGHT
+	}
+	fn read(self: &mut Self, offset: u32) -> u32 {
+		match offset {
+			0 => self.frame,
+			o if o >= Self::PIXELS => self.pixels[(o - Self::PIXELS) as usize],
+			_ => 0,
+		}
+	}
+	fn write(self: &mut Self, offset: u32, value: u32) {
+		match offset {
+			0 => {
+				(self.on_vsync)(self.frame, &self.pixels);
+				self.frame = (self.frame + 1) & isa::WORD_MASK;
+			}
+			o if o >= Self::PIXELS => self.pixels[(o - Self::PIXELS) as usize] = value,
+			_ => {}
+		}
+	}
+}
@@ -0,0 +1,27 @@
+//! Peripherals that can be attached to the memory map, and the ones each
+//! mapping mode starts with.
+
+mod controller;
+mod framebuffer;
+mod sound;
+mod timer;
+
+pub use controller::{Controller, InputScript};
+pub use framebuffer::{FrameBuffer, VsyncHandler};
+pub use sound::{Mixer, Sound};
+pub use timer::Timer;
+
+use crate::vm::Device;
+
+/// The devices a ROM using `mapping` gets, along with where they're attached.
+pub fn for_mapping(mapping: u32) -> Vec<(u32, Box<dyn Device>)> {
+	match mapping {
+		1 => vec![
+			(Timer::ADDRESS, Box::new(Timer::default())),
+			(FrameBuffer::ADDRESS, Box::new(FrameBuffer::default())),
+			(Controller::ADDRESS, Box::new(Controller::default())),
+			(Sound::ADDRESS, Box::new(Sound::default())),
+		],
+		_ => Vec::new(),
+	}
+}
@@ -0,0 +1,99 @@
+use crate::vm::Device;
+use std::{cell::RefCell, rc::Rc};
+
+const CHANNELS: usize = 4;
+const NOISE: usize = 3;
+
+#[derive(Default, Clone, Copy)]
+struct Channel {
+	frequency: u32,
+	volume: u32,
+}
+
+/// Four channels of registers, each taking 4 words: the frequency in Hz, 0 for
+/// silence, then the volume from 0 to 255. Channels 0 to 2 are square waves and
+/// channel 3 is noise. The sound itself is made by a `Mixer`.
+#[derive(Default)]
+pub struct Sound {
+	channels: Rc<RefCell<[Channel; CHANNELS]>>,
+}
+impl Sound {
+	pub const ADDRESS: u32 = 0xf16000;
+
+	/// A mixer playing whatever the ROM sets this device's registers to.
+	pub fn mixer(self: &Self) -> Mixer {
+		Mixer {
+			channels: self.channels.clone(),
+			phases: [0; CHANNELS],
+			noise: 1,
+		}
+	}
+}
+impl Device for Sound {
+	fn size(self: &Self) -> u32 {
+		(CHANNELS * 4) as u32
+	}
+	fn read(self: &mut Self, offset: u32) -> u32 {
+		let channel = self.channels.borrow()[offset as usize / 4];
+		match offset % 4 {
+			0 => channel.frequency,
+			1 => channel.volume,
+			_ => 0,
+		}
+	}
+	fn write(self: &mut Self, offset: u32, value: u32) {
+		let channel = &mut self.channels.borrow_mut()[offset as usize / 4];
+		match offset % 4 {
+			0 => channel.frequency = value,
+			1 => channel.volume = value.min(0xff),
+			_ => {}
+		}
+	}
+}
+
+pub struct Mixer {
+	channels: Rc<RefCell<[Channel; CHANNELS]>>,
+	/// How far through its wave each channel is, as a fraction of 2^32
+	phases: [u32; CHANNELS],
+	/// 15 bit linear feedback shift register for the noise channel
+	noise: u16,
+}
+impl Mixer {
+	pub const SAMPLE_RATE: u32 = 44100;
+	/// Samples in each frame, at 60 frames a second
+	pub const FRAME_SAMPLES: usize = (Self::SAMPLE_RATE / 60) as usize;
+
+	/// Appends `count` mono samples to `out`.
+	pub fn render(self: &mut Self, count: usize, out: &mut Vec<i16>) {
+		let channels = *self.channels.borrow();
+		let steps: Vec<u32> = channels
+			.iter()
+			.map(|c| {
+				let frequency = c.frequency.min(Self::SAMPLE_RATE / 2) as u64;
+				((frequency << 32) / Self::SAMPLE_RATE as u64) as u32
+			})
+			.collect();
+		for _ in 0..count {
+			let mut sample = 0;
+			for (i, channel) in channels.iter().enumerate() {
+				if steps[i] == 0 {
+					continue;
+				}
+				let (phase, wrapped) = self.phases[i].overflowing_add(steps[i]);
+				self.phases[i] = phase;
+				let high = if i == NOISE {
+					if wrapped {
+						let bit = (self.noise ^ (self.noise >> 1)) & 1;
+						self.noise = (self.noise >> 1) | (bit << 14);
+					}
+					self.noise & 1 == 1
+				} else {
+					phase < 0x8000_0000
+				};
+				let level = channel.volume as i32 * 32;
+				sample += if high { level } else { -level };
+			}
+			out.push(sample as i16);
+		}
+	}
+}
@@ -0,0 +1,25 @@
+use crate::vm::Device;
+
+/// Counts the instructions the VM has run, wrapping at 24 bits. Writing to it
+/// sets the count.
+#[derive(Default)]
+pub struct Timer {
+	count: u32,
+}
+impl Timer {
+	pub const ADDRESS: u32 = 0xf00000;
+}
+impl Device for Timer {
+	fn size(self: &Self) -> u32 {
+		1
+	}
+	fn read(self: &mut Self, _offset: u32) -> u32 {
+		self.count
+	}
+	fn write(self: &mut Self, _offset: u32, value: u32) {
+		self.count = value;
+	}
+	fn tick(self: &mut Self) {
+		self.count = (self.count + 1) & isa::WORD_MASK;
+	}
+}
@@ -0,0 +1,13 @@
+#![allow(clippy::needless_arbitrary_self_type)]
+
+//! The Pluto runtime, exposed as a library so other tools can embed the VM.
+//! Hosts can give ROMs extra native functions with `PlutoVM::register_syscall`,
+//! and extra peripherals with `PlutoVM::attach_device`.
+
+pub mod devices;
+pub mod vm;
+
+pub use vm::{
+	Control, Device, Fault, FunctionProfile, Limit, Limits, MemoryAccessor, PLTHeader, PlutoVM,
+	Profile, StackAccess, Status, Usage, VmFault,
+};
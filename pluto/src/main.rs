@@ -1,33 +1,402 @@
-mod vm;
+#![allow(clippy::needless_arbitrary_self_type)]
 
-use std::{fs::File, io::prelude::*, path::PathBuf};
+mod debugger;
+mod profile;
+mod symbols;
+mod trace;
+
+use debugger::Debugger;
+use pluto::{
+	devices::{Controller, FrameBuffer, InputScript, Mixer, Sound},
+	Fault, Limits, PlutoVM, Status,
+};
+use std::{
+	cell::{Cell, RefCell},
+	fs::{self, File},
+	io::{self, prelude::*, BufWriter},
+	path::{Path, PathBuf},
+	process,
+	rc::Rc,
+	time::{Duration, Instant},
+};
 use structopt::StructOpt;
-use vm::PlutoVM;
+use symbols::Symbols;
+use trace::{Filter, Tracer};
 
 #[derive(StructOpt)]
 struct Opt {
 	#[structopt(parse(from_os_str))]
 	rom: PathBuf,
+	/// Write each frame to a PPM file in this directory when the ROM finishes it
+	#[structopt(long, parse(from_os_str))]
+	frames: Option<PathBuf>,
+	/// Replay controller input from a script of frame numbers and the buttons
+	/// held from then on
+	#[structopt(long, parse(from_os_str))]
+	input: Option<PathBuf>,
+	/// Write the sound the ROM makes to a WAV file, at 60 frames a second
+	#[structopt(long, parse(from_os_str))]
+	audio: Option<PathBuf>,
+	/// Run the ROM under an interactive debugger
+	#[structopt(long)]
+	debug: bool,
+	/// Names for addresses, from plasma's symbol file
+	#[structopt(long, parse(from_os_str))]
+	symbols: Option<PathBuf>,
+	/// Log every instruction run, and every function entered and left, to this file
+	#[structopt(long, parse(from_os_str))]
+	trace: Option<PathBuf>,
+	/// Only trace this function, given as an address or a symbol. Can be repeated
+	#[structopt(long, number_of_values = 1)]
+	trace_function: Vec<String>,
+	/// Only trace instructions in this range of addresses, like `0x40..0x80`
+	/// or `main..end`. Can be repeated
+	#[structopt(long, number_of_values = 1)]
+	trace_range: Vec<String>,
+	/// Write how many calls and instructions each function took to this file
+	#[structopt(long, parse(from_os_str))]
+	profile: Option<PathBuf>,
+	/// Write the instructions run under each stack of functions to this file,
+	/// in the collapsed format flamegraph tools read
+	#[structopt(long, parse(from_os_str))]
+	profile_stacks: Option<PathBuf>,
+	/// Stop after running this many instructions
+	#[structopt(long)]
+	max_instructions: Option<u64>,
+	/// Stop after starting this many functions and system calls
+	#[structopt(long)]
+	max_dispatches: Option<u64>,
+	/// Stop when more than this many functions are waiting to run
+	#[structopt(long)]
+	max_function_stack: Option<usize>,
+	/// Stop when the value stack holds more than this many values
+	#[structopt(long)]
+	max_value_stack: Option<usize>,
+	/// Stop after running for about this many seconds. Not available under --debug
+	#[structopt(long, parse(try_from_str = parse_timeout), conflicts_with = "debug")]
+	timeout: Option<Duration>,
 }
 
+/// The exit status when the ROM is stopped for going over a limit, the same
+/// as timeout(1)
+const LIMIT_EXIT: i32 = 124;
+
 fn main() {
 	let opt = Opt::from_args();
 
-	let mut rom = Vec::new();
-	File::open(opt.rom).unwrap().read_to_end(&mut rom).unwrap();
+	let rom = fs::read(&opt.rom).unwrap_or_else(|e| {
+		eprintln!("error: {}: {}", opt.rom.display(), e);
+		process::exit(1);
+	});
+
+	let symbols = match &opt.symbols {
+		Some(path) => Symbols::load(path).unwrap_or_else(|e| {
+			eprintln!("error: {}", e);
+			process::exit(1);
+		}),
+		None => Symbols::default(),
+	};
+	let symbols = Rc::new(symbols);
+	let tracer = opt.trace.as_ref().map(|path| {
+		let filter = trace_filter(&opt, &symbols).unwrap_or_else(|e| {
+			eprintln!("error: {}", e);
+			process::exit(1);
+		});
+		Tracer::new(path, symbols.clone(), filter).unwrap_or_else(|e| {
+			eprintln!("error: {}: {}", path.display(), e);
+			process::exit(1);
+		})
+	});
+	let mut runtime = match Runtime::new(rom, &opt) {
+		Ok(runtime) => runtime,
+		Err(e) => {
+			eprintln!("error: {}: {}", opt.rom.display(), e);
+			process::exit(1);
+		}
+	};
+	runtime.tracer = tracer;
+	let limits = Limits {
+		instructions: opt.max_instructions,
+		dispatches: opt.max_dispatches,
+		function_stack: opt.max_function_stack,
+		value_stack: opt.max_value_stack,
+	};
+	if limits.instructions.is_some()
+		|| limits.dispatches.is_some()
+		|| limits.function_stack.is_some()
+		|| limits.value_stack.is_some()
+	{
+		runtime.vm.set_limits(limits);
+	}
+	if opt.profile.is_some() || opt.profile_stacks.is_some() {
+		runtime.vm.enable_profiling();
+		runtime.profiling = Some(Profiling {
+			report: opt.profile.clone(),
+			stacks: opt.profile_stacks.clone(),
+			symbols: symbols.clone(),
+		});
+	}
+	if opt.debug {
+		Debugger::new(runtime, symbols).run();
+	} else {
+		runtime.run(opt.timeout);
+	}
+}
+
+fn parse_timeout(text: &str) -> Result<Duration, String> {
+	let seconds: f64 = text.parse().map_err(|_| "not a number of seconds")?;
+	if !seconds.is_finite() || seconds < 0.0 {
+		return Err("must be a finite number of seconds, 0 or more".to_string());
+	}
+	Duration::try_from_secs_f64(seconds).map_err(|_| "too long".to_string())
+}
+
+fn trace_filter(opt: &Opt, symbols: &Symbols) -> Result<Filter, String> {
+	let resolve = |text: &str| {
+		symbols
+			.resolve(text)
+			.ok_or_else(|| format!("`{}` isn't an address or a known symbol", text))
+	};
+	let mut filter = Filter::default();
+	for function in opt.trace_function.iter() {
+		filter.functions.push(resolve(function)?);
+	}
+	for range in opt.trace_range.iter() {
+		let mut ends = range.splitn(2, "..");
+		match (ends.next(), ends.next()) {
+			(Some(start), Some(end)) => filter.ranges.push(resolve(start)?..resolve(end)?),
+			_ => return Err(format!("`{}` isn't a range like `start..end`", range)),
+		}
+	}
+	Ok(filter)
+}
+
+struct Input {
+	script: InputScript,
+	/// The frame being drawn, counted up at each vsync
+	frame: Rc<Cell<u32>>,
+	buttons: Rc<Cell<u32>>,
+}
 
-	Runtime::new(rom).run();
+struct Audio {
+	path: PathBuf,
+	samples: Rc<RefCell<Vec<i16>>>,
+}
+
+struct Profiling {
+	report: Option<PathBuf>,
+	stacks: Option<PathBuf>,
+	symbols: Rc<Symbols>,
 }
 
 struct Runtime {
 	vm: PlutoVM,
+	input: Option<Input>,
+	audio: Option<Audio>,
+	tracer: Option<Tracer>,
+	profiling: Option<Profiling>,
 }
 impl Runtime {
-	fn new(rom: Vec<u8>) -> Self {
-		let vm = PlutoVM::new(rom);
-		Self { vm }
+	fn new(rom: Vec<u8>, opt: &Opt) -> Result<Self, String> {
+		let mut vm = PlutoVM::new(rom)?;
+		print!(
+			"Title:     {}\nDeveloper: {}\nPublisher: {}\n",
+			vm.header.title, vm.header.developer, vm.header.publisher
+		);
+		if opt.frames.is_none() && opt.input.is_none() && opt.audio.is_none() {
+			return Ok(Self {
+				vm,
+				input: None,
+				audio: None,
+				tracer: None,
+				profiling: None,
+			});
+		}
+		if vm.header.mapping != 1 {
+			return Err(format!("mapping {} has no devices", vm.header.mapping));
+		}
+
+		let audio = opt.audio.as_ref().map(|path| Audio {
+			path: path.clone(),
+			samples: Rc::new(RefCell::new(Vec::new())),
+		});
+		let mut mixer = audio.as_ref().map(|audio| {
+			let sound = Sound::default();
+			let mixer = (sound.mixer(), audio.samples.clone());
+			vm.attach_device(Sound::ADDRESS, sound);
+			mixer
+		});
+
+		let frame = Rc::new(Cell::new(0));
+		let dir = opt.frames.clone();
+		let next_frame = frame.clone();
+		let framebuffer = FrameBuffer::new(move |finished, pixels| {
+			next_frame.set(finished + 1);
+			if let Some((mixer, samples)) = &mut mixer {
+				mixer.render(Mixer::FRAME_SAMPLES, &mut samples.borrow_mut());
+			}
+			if let Some(dir) = &dir {
+				let path = dir.join(format!("frame_{:06}.ppm", finished));
+				if let Err(e) = write_ppm(&path, pixels) {
+					eprintln!("error: {}: {}", path.display(), e);
+					process::exit(1);
+				}
+			}
+		});
+		vm.attach_device(FrameBuffer::ADDRESS, framebuffer);
+
+		let input = match &opt.input {
+			Some(path) => {
+				let text = fs::read_to_string(path)
+					.map_err(|e| format!("input script {}: {}", path.display(), e))?;
+				let script = InputScript::parse(&text)
+					.map_err(|e| format!("input script {}: {}", path.display(), e))?;
+				let buttons = Rc::new(Cell::new(0));
+				vm.attach_device(Controller::ADDRESS, Controller::new(buttons.clone()));
+				Some(Input {
+					script,
+					frame,
+					buttons,
+				})
+			}
+			None => None,
+		};
+		Ok(Self {
+			vm,
+			input,
+			audio,
+			tracer: None,
+			profiling: None,
+		})
+	}
+	fn tick(self: &mut Self) -> Result<Status, Fault> {
+		self.step(false)
+	}
+	/// Starts the next function without running any of it.
+	fn dispatch(self: &mut Self) -> Result<Status, Fault> {
+		self.step(true)
+	}
+	fn step(self: &mut Self, dispatch: bool) -> Result<Status, Fault> {
+		if let Some(input) = &self.input {
+			input
+				.buttons
+				.set(input.script.buttons_at(input.frame.get()));
+		}
+		let run = |vm: &mut PlutoVM| if dispatch { vm.dispatch() } else { vm.tick() };
+		let tracer = match &mut self.tracer {
+			Some(tracer) => tracer,
+			None => return run(&mut self.vm),
+		};
+		let mut traced = tracer.before(&self.vm, dispatch);
+		let result = run(&mut self.vm);
+		let vm = &self.vm;
+		traced = traced.and_then(|_| tracer.after(vm, &result));
+		if let Err(e) = traced {
+			eprintln!("error: {}: {}", tracer.path().display(), e);
+			process::exit(1);
+		}
+		result
 	}
-	fn run(mut self: Self) {
-		while self.vm.tick() {}
+	fn run(mut self: Self, timeout: Option<Duration>) {
+		// A timeout too long to be a point in time never runs out
+		let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+		let mut ticks: u32 = 0;
+		let code = loop {
+			ticks = ticks.wrapping_add(1);
+			// Checking the clock every tick would slow everything down
+			if ticks & 0xffff == 0 && deadline.is_some_and(|d| Instant::now() >= d) {
+				eprintln!("stopped: timed out after {:?}", timeout.unwrap());
+				break LIMIT_EXIT;
+			}
+			match self.tick() {
+				Ok(Status::Running) => {}
+				Ok(Status::Limit(limit)) => {
+					let usage = self.vm.usage();
+					eprintln!(
+						"stopped: reached the {}, after {} instructions and {} dispatches",
+						limit, usage.instructions, usage.dispatches
+					);
+					break LIMIT_EXIT;
+				}
+				Ok(Status::Finished) => break 0,
+				Ok(Status::Exit(code)) => break code as i32,
+				Err(fault) => {
+					eprintln!("fault: {}", fault);
+					break 1;
+				}
+			}
+		};
+		self.exit(code);
+	}
+	/// Writes out anything recorded during the run and stops.
+	fn exit(self: &mut Self, code: i32) -> ! {
+		if let (Some(profiling), Some(profile)) = (&self.profiling, self.vm.profile()) {
+			let symbols = &profiling.symbols;
+			let mut written = Ok(());
+			if let Some(path) = &profiling.report {
+				written = File::create(path)
+					.and_then(|f| profile::write_report(profile, symbols, BufWriter::new(f)))
+					.map_err(|e| (path, e));
+			}
+			if let (Ok(()), Some(path)) = (&written, &profiling.stacks) {
+				written = File::create(path)
+					.and_then(|f| profile::write_stacks(profile, symbols, BufWriter::new(f)))
+					.map_err(|e| (path, e));
+			}
+			if let Err((path, e)) = written {
+				eprintln!("error: {}: {}", path.display(), e);
+				process::exit(1);
+			}
+		}
+		if let Some(tracer) = &mut self.tracer {
+			if let Err(e) = tracer.flush() {
+				eprintln!("error: {}: {}", tracer.path().display(), e);
+				process::exit(1);
+			}
+		}
+		if let Some(audio) = &self.audio {
+			if let Err(e) = write_wav(&audio.path, &audio.samples.borrow()) {
+				eprintln!("error: {}: {}", audio.path.display(), e);
+				process::exit(1);
+			}
+		}
+		process::exit(code);
+	}
+}
+
+fn write_ppm(path: &Path, pixels: &[u32]) -> io::Result<()> {
+	let mut out = BufWriter::new(File::create(path)?);
+	write!(
+		out,
+		"P6\n{} {}\n255\n",
+		FrameBuffer::WIDTH,
+		FrameBuffer::HEIGHT
+	)?;
+	for pixel in pixels {
+		out.write_all(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8])?;
+	}
+	out.flush()
+}
+
+fn write_wav(path: &Path, samples: &[i16]) -> io::Result<()> {
+	let mut out = BufWriter::new(File::create(path)?);
+	let data_size = samples.len() as u32 * 2;
+	out.write_all(b"RIFF")?;
+	out.write_all(&(36 + data_size).to_le_bytes())?;
+	out.write_all(b"WAVEfmt ")?;
+	out.write_all(&16u32.to_le_bytes())?;
+	// PCM, mono
+	out.write_all(&1u16.to_le_bytes())?;
+	out.write_all(&1u16.to_le_bytes())?;
+	out.write_all(&Mixer::SAMPLE_RATE.to_le_bytes())?;
+	out.write_all(&(Mixer::SAMPLE_RATE * 2).to_le_bytes())?;
+	// 2 byte frames of 16 bit samples
+	out.write_all(&2u16.to_le_bytes())?;
+	out.write_all(&16u16.to_le_bytes())?;
+	out.write_all(b"data")?;
+	out.write_all(&data_size.to_le_bytes())?;
+	for sample in samples {
+		out.write_all(&sample.to_le_bytes())?;
 	}
+	out.flush()
 }
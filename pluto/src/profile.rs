@@ -0,0 +1,61 @@
+use crate::symbols::Symbols;
+use pluto::Profile;
+use std::io::{self, Write};
+
+/// Every function with its calls and instructions, costliest first.
+pub fn write_report<W: Write>(profile: &Profile, symbols: &Symbols, mut out: W) -> io::Result<()> {
+	let mut functions: Vec<_> = profile.functions().iter().collect();
+	functions.sort_by(|(a_ptr, a), (b_ptr, b)| {
+		b.instructions
+			.cmp(&a.instructions)
+			.then(b.calls.cmp(&a.calls))
+			.then(a_ptr.cmp(b_ptr))
+	});
+	let total: u64 = functions.iter().map(|(_, f)| f.instructions).sum();
+	writeln!(
+		out,
+		"{:>12} {:>7} {:>10} {:>10}  function",
+		"instructions", "%", "calls", "per call"
+	)?;
+	for (func_ptr, function) in functions {
+		let percent = match total {
+			0 => 0.0,
+			total => function.instructions as f64 * 100.0 / total as f64,
+		};
+		writeln!(
+			out,
+			"{:12} {:6.2}% {:10} {:10.1}  {}",
+			function.instructions,
+			percent,
+			function.calls,
+			function.instructions as f64 / function.calls as f64,
+			symbols.describe(*func_ptr)
+		)?;
+	}
+	writeln!(out, "{:12} total", total)?;
+	out.flush()
+}
+
+/// The stacks in the collapsed format flamegraph tools read: the functions
+/// separated by `;`, then the instructions run at the top of that stack.
+pub fn write_stacks<W: Write>(profile: &Profile, symbols: &Symbols, mut out: W) -> io::Result<()> {
+	let mut stacks: Vec<(String, u64)> = profile
+		.stacks()
+		.iter()
+		.map(|(stack, count)| {
+			let names: Vec<String> = stack
+				.iter()
+				.map(|func_ptr| match symbols.name(*func_ptr) {
+					Some(name) => name.to_string(),
+					None => format!("func_{:06x}", func_ptr),
+				})
+				.collect();
+			(names.join(";"), *count)
+		})
+		.collect();
+	stacks.sort();
+	for (stack, count) in stacks {
+		writeln!(out, "{} {}", stack, count)?;
+	}
+	out.flush()
+}
@@ -0,0 +1,73 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	fs,
+	path::Path,
+};
+
+/// Names for addresses, read from a symbol file written by plasma. Each line
+/// is an address in hex followed by its name, and whether it is a `label` or
+/// a `def`. Only labels are used to name addresses, since a `def` is more
+/// likely a constant that happens to match an address, but both can be
+/// looked up by name.
+#[derive(Default)]
+pub struct Symbols {
+	/// Labels, by address
+	names: BTreeMap<u32, String>,
+	addresses: HashMap<String, u32>,
+}
+impl Symbols {
+	pub fn load(path: &Path) -> Result<Self, String> {
+		let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+		Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+	}
+	pub fn parse(text: &str) -> Result<Self, String> {
+		let mut symbols = Self::default();
+		for (line_no, line) in text.lines().enumerate() {
+			let mut words = line.split_whitespace();
+			let (address, name) = match (words.next(), words.next()) {
+				(Some(address), Some(name)) => (address, name),
+				(None, _) => continue,
+				_ => {
+					return Err(format!(
+						"line {}: expected an address and a name",
+						line_no + 1
+					))
+				}
+			};
+			let address = u32::from_str_radix(address.trim_start_matches("0x"), 16)
+				.map_err(|_| format!("line {}: bad address `{}`", line_no + 1, address))?;
+			// Older files have no kind, so those are taken as labels
+			if words.next() != Some("def") {
+				symbols.names.insert(address, name.to_string());
+			}
+			symbols.addresses.insert(name.to_string(), address);
+		}
+		Ok(symbols)
+	}
+	pub fn address(self: &Self, name: &str) -> Option<u32> {
+		self.addresses.get(name).copied()
+	}
+	/// An address written as a number or a symbol name.
+	pub fn resolve(self: &Self, text: &str) -> Option<u32> {
+		parse_number(text).or_else(|| self.address(text))
+	}
+	pub fn name(self: &Self, address: u32) -> Option<&str> {
+		self.names.get(&address).map(|name| name.as_str())
+	}
+	/// The address along with the nearest symbol at or before it, like
+	/// `0x000043 <main+3>`.
+	pub fn describe(self: &Self, address: u32) -> String {
+		match self.names.range(..=address).next_back() {
+			Some((start, name)) if *start == address => format!("{:#08x} <{}>", address, name),
+			Some((start, name)) => format!("{:#08x} <{}+{}>", address, name, address - start),
+			None => format!("{:#08x}", address),
+		}
+	}
+}
+
+pub fn parse_number(word: &str) -> Option<u32> {
+	match word.strip_prefix("0x") {
+		Some(hex) => u32::from_str_radix(hex, 16).ok(),
+		None => word.parse().ok(),
+	}
+}
@@ -0,0 +1,143 @@
+use crate::{debugger::instruction, symbols::Symbols};
+use pluto::{Fault, PlutoVM, Status};
+use std::{
+	fs::File,
+	io::{self, BufWriter, Write},
+	ops::Range,
+	path::{Path, PathBuf},
+	rc::Rc,
+};
+
+/// Values from the top of the stack shown with each instruction
+const STACK_TOP: usize = 4;
+
+/// Picks which functions and instructions make it into the trace. With no
+/// filters, everything does.
+#[derive(Default)]
+pub struct Filter {
+	/// Instruction addresses to trace, wherever they are
+	pub ranges: Vec<Range<u32>>,
+	/// Functions whose instructions are all traced, along with entering and
+	/// leaving them
+	pub functions: Vec<u32>,
+}
+impl Filter {
+	fn allows(self: &Self, func_ptr: u32, address: u32) -> bool {
+		(self.ranges.is_empty() && self.functions.is_empty())
+			|| self.functions.contains(&func_ptr)
+			|| self.ranges.iter().any(|range| range.contains(&address))
+	}
+}
+
+/// Logs every instruction the VM runs, and every function it enters and
+/// leaves, to a file. Nothing in the log depends on timing, so traces of two
+/// runs can be diffed.
+pub struct Tracer {
+	out: BufWriter<File>,
+	path: PathBuf,
+	symbols: Rc<Symbols>,
+	filter: Filter,
+	/// The function running during the current tick
+	running: Option<u32>,
+}
+impl Tracer {
+	pub fn new(path: &Path, symbols: Rc<Symbols>, filter: Filter) -> io::Result<Self> {
+		Ok(Self {
+			out: BufWriter::new(File::create(path)?),
+			path: path.to_path_buf(),
+			symbols,
+			filter,
+			running: None,
+		})
+	}
+	/// Logs what the coming tick, or dispatch if `dispatch` is set, is going
+	/// to do.
+	pub fn before(self: &mut Self, vm: &PlutoVM, dispatch: bool) -> io::Result<()> {
+		if let Some((func_ptr, prg_ptr)) = vm.position() {
+			if dispatch {
+				return Ok(());
+			}
+			self.running = Some(func_ptr);
+			let (values, disallowed) = vm.value_stack();
+			return self.instruction(vm, func_ptr, prg_ptr, &values[disallowed..]);
+		}
+		// Between functions the tick starts the next one, and unlike a
+		// dispatch runs its first instruction too
+		let func_ptr = match vm.function_stack().last() {
+			Some(func_ptr) => *func_ptr,
+			None => return Ok(()),
+		};
+		self.running = Some(func_ptr);
+		let signature = vm.signature(func_ptr);
+		if self.filter.allows(func_ptr, func_ptr) {
+			let waiting = vm.function_stack().len() - 1;
+			let function = self.symbols.describe(func_ptr);
+			match signature {
+				Ok((args, ret)) => writeln!(
+					self.out,
+					"enter {} {}->{}, {} waiting",
+					function, args, ret, waiting
+				)?,
+				Err(_) => writeln!(self.out, "enter {}, {} waiting", function, waiting)?,
+			}
+		}
+		match signature {
+			Ok((args, _)) if !dispatch && !isa::syscall::is_syscall(func_ptr) => {
+				let (values, _) = vm.value_stack();
+				let args = values.len().saturating_sub(args as usize);
+				self.instruction(vm, func_ptr, func_ptr + 1, &values[args..])
+			}
+			_ => Ok(()),
+		}
+	}
+	/// Logs the function that was running ending, or the VM faulting.
+	pub fn after(self: &mut Self, vm: &PlutoVM, result: &Result<Status, Fault>) -> io::Result<()> {
+		let func_ptr = match self.running.take() {
+			Some(func_ptr) => func_ptr,
+			None => return Ok(()),
+		};
+		if let Err(fault) = result {
+			writeln!(self.out, "fault: {}", fault)?;
+		} else if vm.position().is_none() && self.filter.allows(func_ptr, func_ptr) {
+			writeln!(
+				self.out,
+				"leave {}, {} waiting",
+				self.symbols.describe(func_ptr),
+				vm.function_stack().len()
+			)?;
+		}
+		Ok(())
+	}
+	pub fn path(self: &Self) -> &Path {
+		&self.path
+	}
+	pub fn flush(self: &mut Self) -> io::Result<()> {
+		self.out.flush()
+	}
+	/// Logs an instruction along with the top of the part of the value stack
+	/// its function can see.
+	fn instruction(
+		self: &mut Self,
+		vm: &PlutoVM,
+		func_ptr: u32,
+		address: u32,
+		visible: &[u32],
+	) -> io::Result<()> {
+		if !self.filter.allows(func_ptr, address) {
+			return Ok(());
+		}
+		let top: Vec<String> = visible
+			.iter()
+			.rev()
+			.take(STACK_TOP)
+			.map(|v| format!("{:#x}", v))
+			.collect();
+		writeln!(
+			self.out,
+			"    {:28} {:20} [{}]",
+			self.symbols.describe(address),
+			instruction(vm.memory(), &self.symbols, address),
+			top.join(" ")
+		)
+	}
+}
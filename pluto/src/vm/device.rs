@@ -0,0 +1,10 @@
+/// A peripheral attached to the memory map. Addresses it is given are
+/// relative to where it was attached.
+pub trait Device {
+	/// The number of words of address space the device takes up.
+	fn size(self: &Self) -> u32;
+	fn read(self: &mut Self, offset: u32) -> u32;
+	fn write(self: &mut Self, offset: u32, value: u32);
+	/// Called once before every instruction the VM runs.
+	fn tick(self: &mut Self) {}
+}
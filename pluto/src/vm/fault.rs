@@ -0,0 +1,76 @@
+use std::fmt;
+
+/// Something a running program did that the VM can't carry on from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VmFault {
+	DivideByZero,
+	UnknownOpcode(u32),
+	/// A function pointer inside the header that isn't a system call
+	UnknownSyscall(u32),
+	/// A function popped or peeked below the values it was given
+	StackUnderflow,
+	/// A function was scheduled without enough values on the stack for its args
+	MissingArgs,
+	/// A function ended with the wrong number of values for its declared returns
+	BadReturnCount,
+	MemoryFault {
+		address: u32,
+		write: bool,
+	},
+}
+
+impl VmFault {
+	/// The number passed to the ROM's fault handler.
+	pub fn code(self: &Self) -> u32 {
+		match self {
+			VmFault::DivideByZero => 1,
+			VmFault::UnknownOpcode(_) => 2,
+			VmFault::StackUnderflow => 3,
+			VmFault::MissingArgs => 4,
+			VmFault::BadReturnCount => 5,
+			VmFault::MemoryFault { .. } => 6,
+			VmFault::UnknownSyscall(_) => 7,
+		}
+	}
+}
+
+impl fmt::Display for VmFault {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			VmFault::DivideByZero => write!(f, "division by zero"),
+			VmFault::UnknownOpcode(op) => write!(f, "unknown opcode {:#08x}", op),
+			VmFault::UnknownSyscall(n) => write!(f, "unknown system call {:#x}", n),
+			VmFault::StackUnderflow => write!(f, "stack underflow"),
+			VmFault::MissingArgs => write!(f, "not enough args on the stack"),
+			VmFault::BadReturnCount => write!(f, "wrong number of returns"),
+			VmFault::MemoryFault {
+				address,
+				write: false,
+			} => write!(f, "cannot read from {:#08x}", address),
+			VmFault::MemoryFault {
+				address,
+				write: true,
+			} => write!(f, "cannot write to {:#08x}", address),
+		}
+	}
+}
+
+/// A fault along with where it happened.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Fault {
+	pub fault: VmFault,
+	/// Address of the instruction that faulted
+	pub address: u32,
+	/// The function that was running
+	pub func_ptr: u32,
+}
+
+impl fmt::Display for Fault {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"{} at {:#08x}, in the function at {:#08x}",
+			self.fault, self.address, self.func_ptr
+		)
+	}
+}
@@ -1,292 +1,349 @@
-use super::memory::MemoryAccessor;
-
-struct StackAccess {
-	stack: Vec<u32>,
-	disallowed: usize,
-	retc: usize,
-}
-impl StackAccess {
-	fn decompose_sig(func_sig: u32) -> (u32, u32) {
-		(func_sig >> 12, func_sig & 0xfff)
-	}
-	fn new(stack: Vec<u32>, func_sig: u32) -> StackAccess {
-		let (argc, retc) = Self::decompose_sig(func_sig);
-		let disallowed = stack.len() - argc as usize;
-		assert!(
-			(argc as usize) <= stack.len(),
-			"Not enough args on the stack"
-		);
-		StackAccess {
-			stack,
-			disallowed,
-			retc: retc as usize,
-		}
-	}
-
-	fn pop(self: &mut Self) -> u32 {
-		assert!(
-			self.stack.len() > self.disallowed,
-			"Function popped too much"
-		);
-		self.stack.pop().unwrap()
-	}
-	fn push(self: &mut Self, value: u32) {
-		self.stack.push(value)
-	}
-	fn push_bool(self: &mut Self, value: bool) {
-		self.push(if value { 1 } else { 0 })
-	}
-	fn peek(self: &Self, depth: u32) -> u32 {
-		assert!(self.stack_height() > depth as usize);
-		self.stack[self.stack.len() - 1 - depth as usize]
-	}
-	fn stack_height(self: &Self) -> usize {
-		self.stack.len() - self.disallowed
-	}
-	fn compat_with(self: &Self, funcs: Vec<u32>) -> bool {
-		self.stack_height() as isize
-			+ funcs
-				.iter()
-				.map(|func| {
-					let (argc, retc) = Self::decompose_sig(*func);
-					retc as isize - argc as isize
-				})
-				.fold(0, |a, b| a + b)
-			== self.retc as isize
-	}
-	fn dispose(self: Self) -> Vec<u32> {
-		self.stack
-	}
-}
+use super::{memory::MemoryAccessor, stack::StackAccess, VmFault};
+use isa::Op;
 
 pub struct FuncExecutor {
 	memory: MemoryAccessor,
+	func_ptr: u32,
 	prg_ptr: u32,
 	stack_access: StackAccess,
 	func_stack: Option<Vec<u32>>,
 }
 impl FuncExecutor {
-	pub fn new<'b>(memory: MemoryAccessor, func_ptr: u32, value_stack: Vec<u32>) -> FuncExecutor {
+	/// Starts the function at `func_ptr`, or hands the value stack back if it
+	/// can't be started.
+	pub fn new(
+		memory: MemoryAccessor,
+		func_ptr: u32,
+		value_stack: Vec<u32>,
+	) -> Result<FuncExecutor, (VmFault, Vec<u32>)> {
 		let prg_ptr = func_ptr + 1;
 
-		let func_sig = memory.read(func_ptr);
-		let stack_access = StackAccess::new(value_stack, func_sig);
+		let func_sig = match memory.read(func_ptr) {
+			Ok(func_sig) => func_sig,
+			Err(fault) => return Err((fault, value_stack)),
+		};
+		let stack_access = StackAccess::new(value_stack, func_sig)?;
 
-		FuncExecutor {
+		Ok(FuncExecutor {
 			memory,
+			func_ptr,
 			prg_ptr,
 			stack_access,
 			func_stack: None,
-		}
+		})
+	}
+	pub fn func_ptr(self: &Self) -> u32 {
+		self.func_ptr
+	}
+	pub fn prg_ptr(self: &Self) -> u32 {
+		self.prg_ptr
+	}
+	pub fn stack_access(self: &Self) -> &StackAccess {
+		&self.stack_access
 	}
-	pub fn tick(self: &mut Self) -> bool {
-		let inst = self.memory.read(self.prg_ptr);
+	/// Runs one instruction, returning false once the function has ended.
+	/// `signature` looks up the signatures of the functions it schedules.
+	pub fn tick(
+		self: &mut Self,
+		signature: impl Fn(u32) -> Result<u32, VmFault>,
+	) -> Result<bool, VmFault> {
+		let inst = self.memory.read(self.prg_ptr)?;
 
-		if inst & 0xfff000 == 0 {
-			self.stack_access.push(self.stack_access.peek(inst & 0xfff));
-		} else {
-			match inst {
-				// Stack Manipulation
-				0x001000 => {
-					// push
-					self.prg_ptr = self.prg_ptr + 1;
-					self.stack_access.push(self.memory.read(self.prg_ptr));
-				}
-				0x001001 => {
-					// drop
-					self.stack_access.pop();
-				}
-				0x001002 => {
-					// load
-					let a = self.stack_access.pop();
-					self.stack_access.push(self.memory.read(a));
-				}
-				0x001003 => {
-					// stor
-					let a = self.stack_access.pop();
-					let v = self.stack_access.pop();
-					self.memory.write(a, v);
-				}
+		let op = Op::decode(inst).ok_or(VmFault::UnknownOpcode(inst))?;
 
-				// Math
-				0x002000 => {
-					// neg
-					let x = self.stack_access.pop();
-					self.stack_access.push_bool(x == 0)
-				}
-				0x002001 => {
-					// add
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push(x + y)
-				}
-				0x002002 => {
-					// sub
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push(x - y);
-				}
-				0x002003 => {
-					// mul
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push(x * y);
-				}
-				0x002005 => {
-					// udiv
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push(x / y);
-				}
-				0x002006 => {
-					// udiv
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push(((x as i32) / (y as i32)) as u32);
-				}
-				0x002008 => {
-					// mod
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push(x % y);
-				}
-				0x002009 => {
-					// rem
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push(((x as i32) % (y as i32)) as u32);
-				}
-				0x00200b => {
-					// not
-					let x = self.stack_access.pop();
-					self.stack_access.push(!x);
-				}
-				0x00200c => {
-					// and
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push(x & y);
-				}
-				0x00200d => {
-					// or
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push(x | y);
-				}
-				0x00200e => {
-					// xor
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push(x ^ y);
-				}
+		match op {
+			// Stack Manipulation
+			Op::Push => {
+				let v = self.memory.read(self.prg_ptr + 1)?;
+				self.prg_ptr += 1;
+				self.stack_access.push(v);
+			}
+			Op::Drop => {
+				self.stack_access.pop()?;
+			}
+			Op::Peek => self
+				.stack_access
+				.push(self.stack_access.peek(isa::embedded(inst))?),
+			Op::Load => {
+				let a = self.stack_access.pop()?;
+				self.stack_access.push(self.memory.read(a)?);
+			}
+			Op::Stor => {
+				let a = self.stack_access.pop()?;
+				let v = self.stack_access.pop()?;
+				self.memory.write(a, v)?;
+			}
 
-				// Comparisons
-				0x003000 => {
-					// eq
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push_bool(x == y);
-				}
-				0x003001 => {
-					//ne
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push_bool(x != y);
-				}
-				0x003002 => {
-					// ult
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push_bool(x < y);
-				}
-				0x003003 => {
-					// slt
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push_bool((x as i32) < (y as i32));
-				}
-				0x003004 => {
-					// ugt
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push_bool(x > y);
-				}
-				0x003005 => {
-					// sgt
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push_bool((x as i32) > (y as i32));
-				}
-				0x003006 => {
-					// ule
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push_bool(x <= y);
-				}
-				0x003007 => {
-					// sle
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push_bool((x as i32) <= (y as i32));
-				}
-				0x003008 => {
-					// uge
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push_bool(x >= y);
-				}
-				0x003009 => {
-					// sge
-					let y = self.stack_access.pop();
-					let x = self.stack_access.pop();
-					self.stack_access.push_bool((x as i32) >= (y as i32));
-				}
+			// Math
+			Op::Neg => {
+				let x = self.stack_access.pop()?;
+				self.stack_access.push_bool(x == 0)
+			}
+			Op::Add => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push(x.wrapping_add(y))
+			}
+			Op::Sub => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push(x.wrapping_sub(y));
+			}
+			Op::Mul => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push(x.wrapping_mul(y));
+			}
+			Op::Udiv => {
+				let y = self.stack_access.pop_divisor()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push(x / y);
+			}
+			Op::Sdiv => {
+				let y = self.stack_access.pop_divisor()?;
+				let x = self.stack_access.pop()?;
+				// -0x800000 / -1 doesn't fit in 24 bits, so wraps back to -0x800000
+				self.stack_access
+					.push((isa::sign_extend(x) / isa::sign_extend(y)) as u32);
+			}
+			Op::Mod => {
+				let y = self.stack_access.pop_divisor()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push(x % y);
+			}
+			Op::Rem => {
+				let y = self.stack_access.pop_divisor()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access
+					.push((isa::sign_extend(x) % isa::sign_extend(y)) as u32);
+			}
+			Op::Not => {
+				let x = self.stack_access.pop()?;
+				self.stack_access.push(!x);
+			}
+			Op::And => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push(x & y);
+			}
+			Op::Or => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push(x | y);
+			}
+			Op::Xor => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push(x ^ y);
+			}
 
-				// End of functio
-				0x004000 => {
-					// ret
-					self.func_stack = Some(Vec::new());
-				}
-				0x004001 => {
-					// jmp
-					self.func_stack = Some(vec![self.stack_access.pop()])
-				}
-				0x004002 => {
-					// if
-					let f1 = self.stack_access.pop();
-					let f2 = self.stack_access.pop();
-					let t = self.stack_access.pop();
-					self.func_stack = Some(vec![if t == 0 { f2 } else { f1 }])
-				}
-				0x004003 => {
-					// call
-					let f1 = self.stack_access.pop();
-					let f2 = self.stack_access.pop();
-					self.func_stack = Some(vec![f2, f1]);
-				}
+			// Comparisons
+			Op::Eq => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push_bool(x == y);
+			}
+			Op::Ne => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push_bool(x != y);
+			}
+			Op::Ult => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push_bool(x < y);
+			}
+			Op::Slt => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access
+					.push_bool(isa::sign_extend(x) < isa::sign_extend(y));
+			}
+			Op::Ugt => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push_bool(x > y);
+			}
+			Op::Sgt => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access
+					.push_bool(isa::sign_extend(x) > isa::sign_extend(y));
+			}
+			Op::Ule => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push_bool(x <= y);
+			}
+			Op::Sle => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access
+					.push_bool(isa::sign_extend(x) <= isa::sign_extend(y));
+			}
+			Op::Uge => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access.push_bool(x >= y);
+			}
+			Op::Sge => {
+				let y = self.stack_access.pop()?;
+				let x = self.stack_access.pop()?;
+				self.stack_access
+					.push_bool(isa::sign_extend(x) >= isa::sign_extend(y));
+			}
 
-				_ => panic!("Unkown opcode {}, at {}", inst, self.prg_ptr),
+			// End of function
+			Op::Ret => {
+				self.func_stack = Some(Vec::new());
+			}
+			Op::Jmp => self.func_stack = Some(vec![self.stack_access.pop()?]),
+			Op::If => {
+				let f1 = self.stack_access.pop()?;
+				let f2 = self.stack_access.pop()?;
+				let t = self.stack_access.pop()?;
+				self.func_stack = Some(vec![if t == 0 { f2 } else { f1 }])
+			}
+			Op::Call => {
+				let f1 = self.stack_access.pop()?;
+				let f2 = self.stack_access.pop()?;
+				self.func_stack = Some(vec![f2, f1]);
 			}
 		}
 		match &self.func_stack {
 			None => {
-				self.prg_ptr = self.prg_ptr + 1;
-				true
+				self.prg_ptr += 1;
+				Ok(true)
 			}
 			Some(f) => {
 				let sigs = f
 					.iter()
-					.map(|func_ptr| self.memory.read(*func_ptr))
-					.collect();
-				assert!(
-					self.stack_access.compat_with(sigs),
-					"Wrong number of returns"
-				);
-				false
+					.map(|func_ptr| signature(*func_ptr))
+					.collect::<Result<_, _>>()?;
+				if !self.stack_access.compat_with(sigs) {
+					return Err(VmFault::BadReturnCount);
+				}
+				Ok(false)
 			}
 		}
 	}
 	pub fn dispose(self: Self) -> (Vec<u32>, Vec<u32>) {
 		(self.func_stack.unwrap(), self.stack_access.dispose())
 	}
+	/// Abandons the function after a fault, dropping its args and anything it pushed.
+	pub fn unwind(self: Self) -> Vec<u32> {
+		self.stack_access.unwind()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const BOUNDARIES: [u32; 5] = [0, 1, 0x7fffff, 0x800000, 0xffffff];
+
+	/// Runs `op` as the only instruction of a function called with `args`,
+	/// returning the value it leaves on top of the stack.
+	fn run(op: Op, args: &[u32]) -> u32 {
+		let rom = vec![
+			0,
+			isa::signature(args.len() as u32, 1),
+			op.opcode(),
+			Op::Ret.opcode(),
+		];
+		let memory = MemoryAccessor::new(isa::mapping::layout(0).unwrap(), rom);
+		let mut executor = match FuncExecutor::new(memory, 1, args.to_vec()) {
+			Ok(executor) => executor,
+			Err((fault, _)) => panic!("{}", fault),
+		};
+		assert_eq!(executor.tick(|_| unreachable!()), Ok(true));
+		executor.stack_access.peek(0).unwrap()
+	}
+
+	#[test]
+	fn math_wraps_to_24_bits() {
+		assert_eq!(run(Op::Sub, &[0, 1]), 0xffffff);
+		assert_eq!(run(Op::Add, &[0xffffff, 1]), 0);
+		assert_eq!(run(Op::Add, &[0x800000, 0x800000]), 0);
+		assert_eq!(run(Op::Mul, &[0x800000, 2]), 0);
+		assert_eq!(run(Op::Mul, &[0xffffff, 0xffffff]), 1);
+		assert_eq!(run(Op::Mul, &[0x7fffff, 0x7fffff]), 0x000001);
+		assert_eq!(run(Op::Not, &[0]), 0xffffff);
+		assert_eq!(run(Op::Not, &[0xffffff]), 0);
+		assert_eq!(run(Op::Not, &[0x7fffff]), 0x800000);
+		assert_eq!(run(Op::Neg, &[0]), 1);
+		assert_eq!(run(Op::Neg, &[0x800000]), 0);
+	}
+
+	#[test]
+	fn math_results_fit_in_a_word() {
+		for op in [Op::Neg, Op::Not] {
+			for x in BOUNDARIES {
+				assert!(run(op, &[x]) <= isa::WORD_MASK, "{:?} {:#x}", op, x);
+			}
+		}
+		let binary = [
+			Op::Add,
+			Op::Sub,
+			Op::Mul,
+			Op::Udiv,
+			Op::Sdiv,
+			Op::Mod,
+			Op::Rem,
+			Op::And,
+			Op::Or,
+			Op::Xor,
+		];
+		for op in binary {
+			for x in BOUNDARIES {
+				for y in BOUNDARIES {
+					if y == 0 && [Op::Udiv, Op::Sdiv, Op::Mod, Op::Rem].contains(&op) {
+						continue;
+					}
+					let v = run(op, &[x, y]);
+					assert!(v <= isa::WORD_MASK, "{:?} {:#x} {:#x}", op, x, y);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn signed_conformance() {
+		#[rustfmt::skip]
+		let table = [
+			// -0x800000 / -1 doesn't fit, and wraps back to -0x800000
+			(Op::Sdiv, 0x800000, 0xffffff, 0x800000),
+			(Op::Sdiv, 0xffffff, 0xffffff, 1),
+			(Op::Sdiv, 0x7fffff, 0xffffff, 0x800001),
+			(Op::Sdiv, 0x800000, 0x7fffff, 0xffffff),
+			(Op::Sdiv, 0xfffff9, 2, 0xfffffd),
+			(Op::Sdiv, 7, 0xfffffe, 0xfffffd),
+			(Op::Sdiv, 0, 0xffffff, 0),
+			(Op::Rem, 0x800000, 0xffffff, 0),
+			(Op::Rem, 0xfffff9, 2, 0xffffff),
+			(Op::Rem, 7, 0xfffffe, 1),
+			(Op::Rem, 0x7fffff, 0x800000, 0x7fffff),
+			(Op::Rem, 0x800000, 0x7fffff, 0xffffff),
+			(Op::Slt, 0xffffff, 0, 1),
+			(Op::Slt, 0, 0xffffff, 0),
+			(Op::Slt, 0x800000, 0x7fffff, 1),
+			(Op::Slt, 0x7fffff, 0x800000, 0),
+			(Op::Slt, 0xffffff, 0xffffff, 0),
+			(Op::Sgt, 0xffffff, 0, 0),
+			(Op::Sgt, 0, 0xffffff, 1),
+			(Op::Sgt, 0x7fffff, 0x800000, 1),
+			(Op::Sgt, 0x800000, 0x7fffff, 0),
+			(Op::Sle, 0xffffff, 0xffffff, 1),
+			(Op::Sle, 0x800000, 0xffffff, 1),
+			(Op::Sle, 0x7fffff, 0x800000, 0),
+			(Op::Sle, 0, 0xffffff, 0),
+			(Op::Sge, 0x800000, 0x800000, 1),
+			(Op::Sge, 0xffffff, 0x800000, 1),
+			(Op::Sge, 0x800000, 0x7fffff, 0),
+			(Op::Sge, 0xffffff, 0, 0),
+		];
+		for (op, x, y, expected) in table {
+			assert_eq!(run(op, &[x, y]), expected, "{:?} {:#x} {:#x}", op, x, y);
+		}
+	}
 }
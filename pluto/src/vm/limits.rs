@@ -0,0 +1,44 @@
+use std::fmt;
+
+/// Caps on how much a program can do, so a ROM that never stops can't hang
+/// whatever is running it. Each is checked before every tick, so the
+/// instruction and dispatch limits are never gone past, but the VM stops
+/// right after the tick that takes a stack over its limit.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Limits {
+	/// Instructions run, not counting system calls
+	pub instructions: Option<u64>,
+	/// Functions and system calls started
+	pub dispatches: Option<u64>,
+	/// Functions waiting to run
+	pub function_stack: Option<usize>,
+	/// Values on the value stack
+	pub value_stack: Option<usize>,
+}
+
+/// How much a program has done so far.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Usage {
+	pub instructions: u64,
+	pub dispatches: u64,
+}
+
+/// Which of the `Limits` stopped the program.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Limit {
+	Instructions,
+	Dispatches,
+	FunctionStack,
+	ValueStack,
+}
+
+impl fmt::Display for Limit {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Limit::Instructions => write!(f, "instruction limit"),
+			Limit::Dispatches => write!(f, "dispatch limit"),
+			Limit::FunctionStack => write!(f, "function stack limit"),
+			Limit::ValueStack => write!(f, "value stack limit"),
+		}
+	}
+}
@@ -1,62 +1,258 @@
+use super::{Device, VmFault};
+use isa::mapping::{Mapping, Region};
 use std::{cell::RefCell, rc::Rc};
 
+/// Memory is mapped in pages of 4K words, so finding the block behind an
+/// address takes a single lookup.
+const PAGE_BITS: u32 = 12;
+const PAGE_SIZE: u32 = 1 << PAGE_BITS;
+const PAGE_COUNT: usize = 1 << (24 - PAGE_BITS);
+/// Marks a page with nothing behind it
+const UNMAPPED: u8 = u8::MAX;
+
 struct MemoryBlock {
+	/// May be shorter than the block, the rest reads as 0 and ignores writes
 	contents: Vec<u32>,
 	offset: u32,
+	size: u32,
 	readable: bool,
 	writeable: bool,
 }
 impl MemoryBlock {
+	fn new(region: Region, contents: Vec<u32>, writeable: bool) -> Self {
+		Self {
+			contents,
+			offset: region.start,
+			size: region.size,
+			readable: true,
+			writeable,
+		}
+	}
 	fn check(self: &Self, address: u32, write: bool) -> bool {
-		address > self.offset
-			&& address - self.offset < self.contents.len() as u32
+		address >= self.offset
+			&& address - self.offset < self.size
 			&& if write { self.writeable } else { self.readable }
 	}
 	fn read(self: &Self, address: u32) -> u32 {
-		assert!(self.check(address, false));
-		self.contents[(address - self.offset) as usize]
+		self.contents
+			.get((address - self.offset) as usize)
+			.copied()
+			.unwrap_or(0)
 	}
 	fn write(self: &mut Self, address: u32, value: u32) {
-		assert!(self.check(address, true));
-		self.contents[(address - self.offset) as usize] = value & 0xffffff;
+		if let Some(word) = self.contents.get_mut((address - self.offset) as usize) {
+			*word = value & 0xffffff;
+		}
+	}
+}
+
+struct DeviceBlock {
+	device: Box<dyn Device>,
+	offset: u32,
+}
+impl DeviceBlock {
+	fn check(self: &Self, address: u32) -> bool {
+		address >= self.offset && address - self.offset < self.device.size()
+	}
+}
+
+enum Backing {
+	Block(MemoryBlock),
+	Device(DeviceBlock),
+}
+impl Backing {
+	fn read(self: &mut Self, address: u32) -> Option<u32> {
+		match self {
+			Backing::Block(b) if b.check(address, false) => Some(b.read(address)),
+			Backing::Device(d) if d.check(address) => Some(d.device.read(address - d.offset)),
+			_ => None,
+		}
+	}
+	fn write(self: &mut Self, address: u32, value: u32) -> Option<()> {
+		match self {
+			Backing::Block(b) if b.check(address, true) => b.write(address, value),
+			Backing::Device(d) if d.check(address) => {
+				d.device.write(address - d.offset, value & 0xffffff)
+			}
+			_ => return None,
+		}
+		Some(())
+	}
+}
+
+struct MemoryMap {
+	backings: Vec<Backing>,
+	/// The backing of each page, as an index into `backings`
+	pages: Vec<u8>,
+	/// Indices into `backings` of every device, to tick them
+	devices: Vec<usize>,
+}
+impl MemoryMap {
+	/// Maps `size` words starting at `offset`, covering whatever was there
+	/// before. Backings that end up completely covered are replaced, so
+	/// mapping over the same pages again doesn't use up more of them.
+	fn add(self: &mut Self, offset: u32, size: u32, backing: Backing) {
+		assert_eq!(
+			offset % PAGE_SIZE,
+			0,
+			"Memory blocks must start on a page boundary"
+		);
+		let first = (offset >> PAGE_BITS) as usize;
+		let count = ((size + PAGE_SIZE - 1) >> PAGE_BITS) as usize;
+		assert!(
+			first + count <= PAGE_COUNT,
+			"Memory blocks must fit in 24 bits"
+		);
+		// The backings still showing through somewhere once this is added
+		let mut visible = vec![false; self.backings.len()];
+		let outside = self.pages[..first]
+			.iter()
+			.chain(&self.pages[first + count..]);
+		for page in outside.filter(|page| **page != UNMAPPED) {
+			visible[*page as usize] = true;
+		}
+		let index = visible
+			.iter()
+			.position(|visible| !visible)
+			.unwrap_or(self.backings.len());
+		assert!(index < UNMAPPED as usize, "Too many memory blocks");
+		for page in self.pages[first..first + count].iter_mut() {
+			*page = index as u8;
+		}
+		// Devices that have been completely covered up stop being ticked
+		self.devices.retain(|device| visible[*device]);
+		if let Backing::Device(_) = backing {
+			self.devices.push(index);
+		}
+		if index < self.backings.len() {
+			self.backings[index] = backing;
+		} else {
+			self.backings.push(backing);
+		}
+	}
+	fn add_block(self: &mut Self, block: MemoryBlock) {
+		self.add(block.offset, block.size, Backing::Block(block));
+	}
+	fn backing(self: &mut Self, address: u32) -> Option<&mut Backing> {
+		let index = *self.pages.get((address >> PAGE_BITS) as usize)?;
+		self.backings.get_mut(index as usize)
 	}
 }
 
 #[derive(Clone)]
 pub struct MemoryAccessor {
-	mem_blocks: Rc<RefCell<Vec<MemoryBlock>>>,
+	map: Rc<RefCell<MemoryMap>>,
 }
 impl MemoryAccessor {
-	pub fn new(mapping: u32, rom: Vec<u32>) -> Self {
-		let mem_blocks = match mapping {
-			0 => vec![MemoryBlock {
-				contents: rom,
-				offset: 0,
-				readable: true,
-				writeable: false,
-			}],
-			_ => panic!("Unkown Mapping Mode"),
+	/// Lays out memory for a mapping mode, which the ROM has to fit in.
+	pub(crate) fn new(mapping: Mapping, rom: Vec<u32>) -> Self {
+		let mut map = MemoryMap {
+			backings: Vec::new(),
+			pages: vec![UNMAPPED; PAGE_COUNT],
+			devices: Vec::new(),
+		};
+		let rom_region = Region {
+			start: mapping.rom.start,
+			size: rom.len() as u32,
 		};
+		map.add_block(MemoryBlock::new(rom_region, rom, false));
+		if let Some(ram) = mapping.ram {
+			map.add_block(MemoryBlock::new(ram, vec![0; ram.size as usize], true));
+		}
+		if let Some(io) = mapping.io {
+			map.add_block(MemoryBlock::new(io, Vec::new(), true));
+		}
 		Self {
-			mem_blocks: Rc::new(RefCell::new(mem_blocks)),
+			map: Rc::new(RefCell::new(map)),
 		}
 	}
-	pub fn read(self: &Self, address: u32) -> u32 {
-		self.mem_blocks
-			.borrow()
-			.iter()
-			.filter(|b| b.check(address, false))
-			.next()
-			.unwrap()
-			.read(address)
+	/// Attaches a device at `start`, which has to be on a 4K word page
+	/// boundary. It covers whole pages, hiding anything else mapped there.
+	pub(crate) fn attach(self: &mut Self, start: u32, device: Box<dyn Device>) {
+		let size = device.size();
+		self.map.borrow_mut().add(
+			start,
+			size,
+			Backing::Device(DeviceBlock {
+				device,
+				offset: start,
+			}),
+		);
+	}
+	pub(crate) fn tick_devices(self: &mut Self) {
+		let mut map = self.map.borrow_mut();
+		let map = &mut *map;
+		for index in map.devices.iter() {
+			if let Backing::Device(d) = &mut map.backings[*index] {
+				d.device.tick();
+			}
+		}
+	}
+	pub fn read(self: &Self, address: u32) -> Result<u32, VmFault> {
+		self.map
+			.borrow_mut()
+			.backing(address)
+			.and_then(|b| b.read(address))
+			.ok_or(VmFault::MemoryFault {
+				address,
+				write: false,
+			})
 	}
-	pub fn write(self: &mut Self, address: u32, value: u32) {
-		self.mem_blocks
+	pub fn write(self: &mut Self, address: u32, value: u32) -> Result<(), VmFault> {
+		self.map
 			.borrow_mut()
-			.iter_mut()
-			.filter(|b| b.check(address, true))
-			.next()
-			.unwrap()
-			.write(address, value)
+			.backing(address)
+			.and_then(|b| b.write(address, value))
+			.ok_or(VmFault::MemoryFault {
+				address,
+				write: true,
+			})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn first_word_of_a_block() {
+		let layout = isa::mapping::layout(1).unwrap();
+		let mut memory = MemoryAccessor::new(layout, vec![0x504c54, 1, 2]);
+		assert_eq!(memory.read(0), Ok(0x504c54));
+		assert!(memory.write(0, 5).is_err());
+		assert!(memory.read(3).is_err());
+		let ram = layout.ram.unwrap().start;
+		assert_eq!(memory.write(ram, 7), Ok(()));
+		assert_eq!(memory.read(ram), Ok(7));
+		let io = layout.io.unwrap().start;
+		assert_eq!(memory.write(io, 9), Ok(()));
+		assert_eq!(memory.read(io), Ok(0));
+	}
+
+	struct Register(u32);
+	impl Device for Register {
+		fn size(self: &Self) -> u32 {
+			1
+		}
+		fn read(self: &mut Self, _offset: u32) -> u32 {
+			self.0
+		}
+		fn write(self: &mut Self, _offset: u32, value: u32) {
+			self.0 = value;
+		}
+	}
+
+	#[test]
+	fn attach_over_the_same_page() {
+		let layout = isa::mapping::layout(1).unwrap();
+		let mut memory = MemoryAccessor::new(layout, vec![0x504c54]);
+		let io = layout.io.unwrap().start;
+		for i in 0..1000 {
+			memory.attach(io, Box::new(Register(i)));
+		}
+		assert_eq!(memory.read(io), Ok(999));
+		assert_eq!(memory.read(io + PAGE_SIZE), Ok(0));
+		assert_eq!(memory.map.borrow().backings.len(), 4);
+		assert_eq!(memory.map.borrow().devices.len(), 1);
 	}
 }
@@ -1,12 +1,30 @@
+mod device;
+mod fault;
 mod func_execute;
+mod limits;
 mod memory;
+mod profile;
+mod stack;
+mod syscall;
+
+pub use device::Device;
+pub use fault::{Fault, VmFault};
+pub use limits::{Limit, Limits, Usage};
+pub use memory::MemoryAccessor;
+pub use profile::{FunctionProfile, Profile};
+pub use stack::StackAccess;
+pub use syscall::{Control, Handler};
 
 use func_execute::FuncExecutor;
-use memory::MemoryAccessor;
+use isa::header;
 use std::iter::FromIterator;
+use syscall::Syscalls;
 
 pub struct InteruptVectors {
-	reset: u32,
+	/// Called with the fault code, address and function pointer when a fault
+	/// happens, or 0 to stop the VM instead
+	pub fault: u32,
+	pub reset: u32,
 }
 
 pub struct PLTHeader {
@@ -23,12 +41,15 @@ impl PLTHeader {
 	fn create(raw: &[u32]) -> Self {
 		Self {
 			magic: raw[0x0],
-			features: raw[0x1],
-			mapping: raw[0x2],
-			vectors: InteruptVectors { reset: raw[0xf] },
-			title: from_utf32(&raw[0x10..0x20]),
-			developer: from_utf32(&raw[0x20..0x30]),
-			publisher: from_utf32(&raw[0x30..0x40]),
+			features: raw[header::FEATURES],
+			mapping: raw[header::MAPPING],
+			vectors: InteruptVectors {
+				fault: raw[header::FAULT],
+				reset: raw[header::RESET],
+			},
+			title: from_utf32(header_string(raw, header::TITLE)),
+			developer: from_utf32(header_string(raw, header::DEVELOPER)),
+			publisher: from_utf32(header_string(raw, header::PUBLISHER)),
 		}
 	}
 }
@@ -41,11 +62,21 @@ fn from_utf32(raw: &[u32]) -> String {
 	)
 }
 
-fn convert_24_bit(bytes: Vec<u8>) -> Vec<u32> {
-	bytes
-		.chunks(3)
-		.map(|i| u32::from_be_bytes([0, i[0], i[1], i[2]]))
-		.collect()
+fn header_string(raw: &[u32], start: usize) -> &[u32] {
+	&raw[start..start + header::STRING_LEN]
+}
+
+/// What the VM is doing after a tick.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+	Running,
+	/// The function stack ran out
+	Finished,
+	/// The program called the exit system call with this code
+	Exit(u32),
+	/// The program was stopped before the next tick for going over one of
+	/// its `Limits`
+	Limit(Limit),
 }
 
 enum Func {
@@ -58,64 +89,335 @@ pub struct PlutoVM {
 	pub header: PLTHeader,
 	func: Func,
 	function_stack: Vec<u32>,
+	syscalls: Syscalls,
+	profile: Option<Profile>,
+	limits: Option<Limits>,
+	usage: Usage,
+	/// While the fault handler, or anything it scheduled, is still to run,
+	/// the length the function stack had when it was scheduled
+	handling_fault: Option<usize>,
 }
 impl PlutoVM {
-	pub fn new(bytes: Vec<u8>) -> Self {
-		let rom = convert_24_bit(bytes);
-		let header = PLTHeader::create(&rom[0..0x40]);
-		assert_eq!(header.magic, 0x504c54);
-		assert_eq!(header.features, 0);
-		print!(
-			"Title:     {}\nDeveloper: {}\nPublisher: {}\n",
-			header.title, header.developer, header.publisher
-		);
-		let memory = MemoryAccessor::new(header.mapping, rom);
+	/// Loads a ROM, or explains why it can't be run.
+	pub fn new(bytes: Vec<u8>) -> Result<Self, String> {
+		let rom = isa::words_from_bytes(&bytes);
+		if rom.len() < header::SIZE || rom[0x0] != header::MAGIC {
+			return Err("not a PLT ROM, the magic number is missing".to_string());
+		}
+		let header = PLTHeader::create(&rom[0..header::SIZE]);
+		if header.features != 0 {
+			return Err(format!("unsupported features {:#x}", header.features));
+		}
+		let mapping = match isa::mapping::layout(header.mapping) {
+			Some(mapping) => mapping,
+			None => return Err(format!("unknown mapping mode {}", header.mapping)),
+		};
+		if rom.len() > mapping.rom.size as usize {
+			return Err(format!(
+				"the ROM is {:#x} words, but mapping {} only has room for {:#x}",
+				rom.len(),
+				header.mapping,
+				mapping.rom.size
+			));
+		}
+		let mut memory = MemoryAccessor::new(mapping, rom);
+		for (start, device) in crate::devices::for_mapping(header.mapping) {
+			memory.attach(start, device);
+		}
 		let function_stack = vec![header.vectors.reset];
-		Self {
+		Ok(Self {
 			memory,
 			header,
 			func: Func::Stack(Vec::new()),
 			function_stack,
+			syscalls: Syscalls::new(),
+			profile: None,
+			limits: None,
+			usage: Usage::default(),
+			handling_fault: None,
+		})
+	}
+	/// Adds a system call implemented by the host, replacing any built in one
+	/// with the same number. The ROM calls it by scheduling `number`, which has
+	/// to be below 0x40, and it is checked against `args` and `ret` the same
+	/// way as a function with that signature.
+	pub fn register_syscall(
+		self: &mut Self,
+		number: u32,
+		args: u32,
+		ret: u32,
+		handler: impl FnMut(&mut StackAccess, &mut MemoryAccessor) -> Result<Control, VmFault> + 'static,
+	) {
+		assert!(
+			isa::syscall::is_syscall(number),
+			"System call numbers must be below {:#x}",
+			header::SIZE
+		);
+		assert!(
+			args <= 0xfff && ret <= 0xfff,
+			"System call signatures are limited to 0xfff args and returns"
+		);
+		self.syscalls.register(number, args, ret, Box::new(handler));
+	}
+	/// Attaches a device to the memory map at `start`, which has to be on a
+	/// 4K word page boundary. It covers whole pages, replacing whatever was
+	/// mapped there, including the mapping mode's own devices.
+	pub fn attach_device(self: &mut Self, start: u32, device: impl Device + 'static) {
+		self.memory.attach(start, Box::new(device));
+	}
+	/// The running function and the address of its next instruction, or
+	/// `None` between functions.
+	pub fn position(self: &Self) -> Option<(u32, u32)> {
+		match &self.func {
+			Func::Executor(e) => Some((e.func_ptr(), e.prg_ptr())),
+			Func::Stack(_) => None,
 		}
 	}
-	pub fn tick(self: &mut Self) -> bool {
-		let func_executor: &mut FuncExecutor = match &mut self.func {
-			Func::Stack(stack) => {
-				let func_ptr = match self.function_stack.pop() {
-					Some(func_ptr) => func_ptr,
-					None => {
-						// Program Over
-						if stack.len() > 0 {
-							println!("Values left on the stack:");
-							for i in stack.iter().rev() {
-								println!("{}", i);
-							}
-						};
-						return false;
-					}
-				};
-				self.func = Func::Executor(FuncExecutor::new(
-					self.memory.clone(),
-					func_ptr,
-					std::mem::take(stack),
-				));
-				match &mut self.func {
-					Func::Executor(e) => e,
-					Func::Stack(_) => panic!("The world doesn't make sense anymore."),
+	/// The value stack from the bottom up, along with how many values at the
+	/// bottom the running function can't touch.
+	pub fn value_stack(self: &Self) -> (&[u32], usize) {
+		match &self.func {
+			Func::Executor(e) => (e.stack_access().values(), e.stack_access().disallowed()),
+			Func::Stack(stack) => (stack, 0),
+		}
+	}
+	/// The functions waiting to run, with the next one last.
+	pub fn function_stack(self: &Self) -> &[u32] {
+		&self.function_stack
+	}
+	pub fn memory(self: &Self) -> &MemoryAccessor {
+		&self.memory
+	}
+	/// The args and returns of the function, or system call, at `func_ptr`.
+	pub fn signature(self: &Self, func_ptr: u32) -> Result<(u32, u32), VmFault> {
+		signature(&self.syscalls, &self.memory, func_ptr).map(isa::decompose_signature)
+	}
+	/// Starts counting the calls and instructions of each function.
+	pub fn enable_profiling(self: &mut Self) {
+		self.profile = Some(Profile::new(self.function_stack.len()));
+	}
+	pub fn profile(self: &Self) -> Option<&Profile> {
+		self.profile.as_ref()
+	}
+	pub fn set_limits(self: &mut Self, limits: Limits) {
+		self.limits = Some(limits);
+	}
+	pub fn usage(self: &Self) -> Usage {
+		self.usage
+	}
+	/// The limit the next tick, or dispatch if `tick` is false, would go
+	/// over, if any.
+	fn limit_reached(self: &Self, limits: &Limits, tick: bool) -> Option<Limit> {
+		let over = |max: Option<u64>, used: u64| max.is_some_and(|max| used >= max);
+		if limits
+			.value_stack
+			.is_some_and(|max| self.value_stack().0.len() > max)
+		{
+			return Some(Limit::ValueStack);
+		}
+		if limits
+			.function_stack
+			.is_some_and(|max| self.function_stack.len() > max)
+		{
+			return Some(Limit::FunctionStack);
+		}
+		// Starting a function runs its first instruction in the same tick
+		let runs_instruction = match (&self.func, self.function_stack.last()) {
+			(Func::Executor(_), _) => true,
+			(Func::Stack(_), Some(func_ptr)) => {
+				if over(limits.dispatches, self.usage.dispatches) {
+					return Some(Limit::Dispatches);
 				}
+				tick && !isa::syscall::is_syscall(*func_ptr)
 			}
+			(Func::Stack(_), None) => false,
+		};
+		if runs_instruction && over(limits.instructions, self.usage.instructions) {
+			return Some(Limit::Instructions);
+		}
+		None
+	}
+	/// Starts the next function without running any of it, so a debugger can
+	/// stop before its first instruction. A system call is run in full, and
+	/// nothing happens while a function is already running.
+	pub fn dispatch(self: &mut Self) -> Result<Status, Fault> {
+		if let Func::Executor(_) = self.func {
+			return Ok(Status::Running);
+		}
+		if let Some(limits) = &self.limits {
+			if let Some(limit) = self.limit_reached(limits, false) {
+				return Ok(Status::Limit(limit));
+			}
+		}
+		self.start_next()
+	}
+	/// Runs a single instruction, or system call, returning the fault if one
+	/// happens that the ROM doesn't handle itself.
+	pub fn tick(self: &mut Self) -> Result<Status, Fault> {
+		if let Some(limits) = &self.limits {
+			if let Some(limit) = self.limit_reached(limits, true) {
+				return Ok(Status::Limit(limit));
+			}
+		}
+		// Starting a function runs its first instruction in the same tick
+		if let Func::Stack(_) = self.func {
+			match self.start_next()? {
+				Status::Running => {}
+				status => return Ok(status),
+			}
+		}
+		let func_executor = match &mut self.func {
 			Func::Executor(e) => e,
+			Func::Stack(_) => return Ok(Status::Running),
 		};
-		if !func_executor.tick() {
-			let (mut func_stack, value_stack) =
-				match std::mem::replace(&mut self.func, Func::Stack(Vec::new())) {
-					Func::Executor(e) => e,
-					Func::Stack(_) => panic!("The world doesn't make sense anymore."),
+		self.memory.tick_devices();
+		let (syscalls, memory) = (&self.syscalls, &self.memory);
+		let signature = |func_ptr| signature(syscalls, memory, func_ptr);
+		let result = func_executor.tick(signature);
+		self.usage.instructions += 1;
+		if let Some(profile) = &mut self.profile {
+			profile.instruction();
+		}
+		match result {
+			Ok(true) => {}
+			Ok(false) => {
+				let (mut func_stack, value_stack) =
+					match std::mem::replace(&mut self.func, Func::Stack(Vec::new())) {
+						Func::Executor(e) => e,
+						Func::Stack(_) => panic!("The world doesn't make sense anymore."),
+					}
+					.dispose();
+				if let Some(profile) = &mut self.profile {
+					profile.end(&func_stack);
 				}
-				.dispose();
-			self.function_stack.append(&mut func_stack);
-			self.func = Func::Stack(value_stack);
+				self.function_stack.append(&mut func_stack);
+				self.func = Func::Stack(value_stack);
+			}
+			Err(fault) => {
+				let fault = Fault {
+					fault,
+					address: func_executor.prg_ptr(),
+					func_ptr: func_executor.func_ptr(),
+				};
+				let stack = match std::mem::replace(&mut self.func, Func::Stack(Vec::new())) {
+					Func::Executor(e) => e.unwind(),
+					Func::Stack(_) => panic!("The world doesn't make sense anymore."),
+				};
+				return self.fault(fault, stack);
+			}
+		}
+		Ok(Status::Running)
+	}
+	/// Starts the next function, or runs the next system call.
+	fn start_next(self: &mut Self) -> Result<Status, Fault> {
+		// The handler is done once everything it scheduled has run
+		if let Some(depth) = self.handling_fault {
+			if self.function_stack.len() <= depth {
+				self.handling_fault = None;
+			}
+		}
+		let stack = match &mut self.func {
+			Func::Stack(stack) => stack,
+			Func::Executor(_) => return Ok(Status::Running),
+		};
+		let func_ptr = match self.function_stack.pop() {
+			Some(func_ptr) => func_ptr,
+			None => {
+				// Program Over
+				if !stack.is_empty() {
+					println!("Values left on the stack:");
+					for i in stack.iter().rev() {
+						println!("{}", i);
+					}
+				};
+				return Ok(Status::Finished);
+			}
+		};
+		let stack = std::mem::take(stack);
+		self.usage.dispatches += 1;
+		if let Some(profile) = &mut self.profile {
+			profile.start(func_ptr);
 		}
-		true
+		if isa::syscall::is_syscall(func_ptr) {
+			return self.syscall(func_ptr, stack);
+		}
+		match FuncExecutor::new(self.memory.clone(), func_ptr, stack) {
+			Ok(e) => self.func = Func::Executor(e),
+			Err((fault, stack)) => {
+				let fault = Fault {
+					fault,
+					address: func_ptr,
+					func_ptr,
+				};
+				return self.fault(fault, stack);
+			}
+		}
+		Ok(Status::Running)
+	}
+	/// Runs a system call in place of a function, with the same checks on
+	/// its args and returns.
+	fn syscall(self: &mut Self, number: u32, stack: Vec<u32>) -> Result<Status, Fault> {
+		let fault = |fault| Fault {
+			fault,
+			address: number,
+			func_ptr: number,
+		};
+		let sig = match self.syscalls.signature(number) {
+			Ok(sig) => sig,
+			Err(f) => return self.fault(fault(f), stack),
+		};
+		let mut stack_access = match StackAccess::new(stack, sig) {
+			Ok(stack_access) => stack_access,
+			Err((f, stack)) => return self.fault(fault(f), stack),
+		};
+		let control = match self
+			.syscalls
+			.call(number, &mut stack_access, &mut self.memory)
+		{
+			Ok(control) => control,
+			Err(f) => return self.fault(fault(f), stack_access.unwind()),
+		};
+		if !stack_access.compat_with(Vec::new()) {
+			return self.fault(fault(VmFault::BadReturnCount), stack_access.unwind());
+		}
+		self.func = Func::Stack(stack_access.dispose());
+		if let Some(profile) = &mut self.profile {
+			profile.end(&[]);
+		}
+		Ok(match control {
+			Control::Continue => Status::Running,
+			Control::Exit(code) => Status::Exit(code),
+		})
+	}
+	/// Abandons the faulting function and schedules the ROM's fault handler,
+	/// if it has one.
+	fn fault(self: &mut Self, fault: Fault, mut stack: Vec<u32>) -> Result<Status, Fault> {
+		let handler = self.header.vectors.fault;
+		// A fault in the handler, or in something it scheduled, could
+		// otherwise keep calling the handler forever
+		if handler == 0 || self.handling_fault.is_some() {
+			if let Some(profile) = &mut self.profile {
+				profile.end(&[]);
+			}
+			self.func = Func::Stack(stack);
+			return Err(fault);
+		}
+		if let Some(profile) = &mut self.profile {
+			profile.end(&[handler]);
+		}
+		stack.extend(&[fault.fault.code(), fault.address, fault.func_ptr]);
+		self.func = Func::Stack(stack);
+		self.handling_fault = Some(self.function_stack.len());
+		self.function_stack.push(handler);
+		Ok(Status::Running)
+	}
+}
+
+/// The signature of a function, or of the system call a pointer refers to.
+fn signature(syscalls: &Syscalls, memory: &MemoryAccessor, func_ptr: u32) -> Result<u32, VmFault> {
+	if isa::syscall::is_syscall(func_ptr) {
+		syscalls.signature(func_ptr)
+	} else {
+		memory.read(func_ptr)
 	}
 }
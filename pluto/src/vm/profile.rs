@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct FunctionProfile {
+	/// How many times the function was started
+	pub calls: u64,
+	/// Instructions run by the function itself
+	pub instructions: u64,
+}
+
+/// Instruction counts per function, and per stack of functions.
+///
+/// Pluto has no call stack, so the stacks here are made up from which
+/// function scheduled which. `jmp`, `if` and the second function given to
+/// `call` carry on in place of the function that scheduled them, while the
+/// function `call` runs first is treated as being called from it.
+#[derive(Default)]
+pub struct Profile {
+	functions: HashMap<u32, FunctionProfile>,
+	stacks: HashMap<Vec<u32>, u64>,
+	/// The stack each waiting function will run under, alongside the VM's
+	/// function stack
+	pending: Vec<Vec<u32>>,
+	/// The stack of the running function, ending with it
+	current: Option<Vec<u32>>,
+	instructions: u64,
+}
+impl Profile {
+	pub(crate) fn new(waiting: usize) -> Self {
+		Self {
+			pending: vec![Vec::new(); waiting],
+			..Self::default()
+		}
+	}
+	pub fn functions(self: &Self) -> &HashMap<u32, FunctionProfile> {
+		&self.functions
+	}
+	/// Instructions run by the last function in each stack.
+	pub fn stacks(self: &Self) -> &HashMap<Vec<u32>, u64> {
+		&self.stacks
+	}
+
+	pub(crate) fn start(self: &mut Self, func_ptr: u32) {
+		let mut stack = self.pending.pop().unwrap_or_default();
+		stack.push(func_ptr);
+		self.functions.entry(func_ptr).or_default().calls += 1;
+		self.current = Some(stack);
+		self.instructions = 0;
+	}
+	pub(crate) fn instruction(self: &mut Self) {
+		self.instructions += 1;
+	}
+	/// Records the running function ending, having scheduled `scheduled`.
+	pub(crate) fn end(self: &mut Self, scheduled: &[u32]) {
+		let stack = match self.current.take() {
+			Some(stack) => stack,
+			None => return,
+		};
+		let func_ptr = stack[stack.len() - 1];
+		self.functions.entry(func_ptr).or_default().instructions += self.instructions;
+		if self.instructions > 0 {
+			*self.stacks.entry(stack.clone()).or_default() += self.instructions;
+		}
+		let caller = &stack[..stack.len() - 1];
+		for i in 0..scheduled.len() {
+			if i > 0 && i == scheduled.len() - 1 {
+				self.pending.push(stack.clone());
+			} else {
+				self.pending.push(caller.to_vec());
+			}
+		}
+	}
+}
@@ -0,0 +1,78 @@
+use super::VmFault;
+
+/// The value stack as seen by a single function, which can only touch its own
+/// args and the values it has pushed.
+pub struct StackAccess {
+	stack: Vec<u32>,
+	disallowed: usize,
+	retc: usize,
+}
+impl StackAccess {
+	pub(crate) fn new(stack: Vec<u32>, func_sig: u32) -> Result<StackAccess, (VmFault, Vec<u32>)> {
+		let (argc, retc) = isa::decompose_signature(func_sig);
+		if (argc as usize) > stack.len() {
+			return Err((VmFault::MissingArgs, stack));
+		}
+		Ok(StackAccess {
+			disallowed: stack.len() - argc as usize,
+			stack,
+			retc: retc as usize,
+		})
+	}
+
+	pub fn pop(self: &mut Self) -> Result<u32, VmFault> {
+		if self.stack.len() <= self.disallowed {
+			return Err(VmFault::StackUnderflow);
+		}
+		Ok(self.stack.pop().unwrap())
+	}
+	pub(crate) fn pop_divisor(self: &mut Self) -> Result<u32, VmFault> {
+		match self.pop()? {
+			0 => Err(VmFault::DivideByZero),
+			y => Ok(y),
+		}
+	}
+	/// Pushes a value, wrapping it to a 24-bit word.
+	pub fn push(self: &mut Self, value: u32) {
+		self.stack.push(value & isa::WORD_MASK)
+	}
+	pub fn push_bool(self: &mut Self, value: bool) {
+		self.push(if value { 1 } else { 0 })
+	}
+	pub fn peek(self: &Self, depth: u32) -> Result<u32, VmFault> {
+		if self.stack_height() <= depth as usize {
+			return Err(VmFault::StackUnderflow);
+		}
+		Ok(self.stack[self.stack.len() - 1 - depth as usize])
+	}
+	pub fn stack_height(self: &Self) -> usize {
+		self.stack.len() - self.disallowed
+	}
+	pub(crate) fn compat_with(self: &Self, funcs: Vec<u32>) -> bool {
+		self.stack_height() as isize
+			+ funcs
+				.iter()
+				.map(|func| {
+					let (argc, retc) = isa::decompose_signature(*func);
+					retc as isize - argc as isize
+				})
+				.sum::<isize>()
+			== self.retc as isize
+	}
+	/// The whole value stack, including the part the function can't touch.
+	pub(crate) fn values(self: &Self) -> &[u32] {
+		&self.stack
+	}
+	/// How many values at the bottom of the stack the function can't touch.
+	pub(crate) fn disallowed(self: &Self) -> usize {
+		self.disallowed
+	}
+	pub(crate) fn dispose(self: Self) -> Vec<u32> {
+		self.stack
+	}
+	/// The stack as it was before the function's args, for when it faults.
+	pub(crate) fn unwind(mut self: Self) -> Vec<u32> {
+		self.stack.truncate(self.disallowed);
+		self.stack
+	}
+}
@@ -0,0 +1,114 @@
+use super::{memory::MemoryAccessor, stack::StackAccess, VmFault};
+use isa::syscall;
+use std::{
+	collections::HashMap,
+	io::{self, Read, Write},
+	time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// What the VM should do once a system call has returned.
+pub enum Control {
+	Continue,
+	Exit(u32),
+}
+
+/// Implements a system call. It gets the stack as seen by a function with the
+/// system call's signature, so can only pop its own args.
+pub type Handler =
+	Box<dyn FnMut(&mut StackAccess, &mut MemoryAccessor) -> Result<Control, VmFault>>;
+
+struct Syscall {
+	args: u32,
+	ret: u32,
+	handler: Handler,
+}
+
+pub struct Syscalls {
+	calls: HashMap<u32, Syscall>,
+}
+impl Syscalls {
+	pub fn new() -> Self {
+		let mut syscalls = Self {
+			calls: HashMap::new(),
+		};
+		syscalls.builtin(syscall::PUTCHAR, |stack, _| {
+			let c = std::char::from_u32(stack.pop()?).unwrap_or(std::char::REPLACEMENT_CHARACTER);
+			let mut stdout = io::stdout();
+			// Nobody is left to read the output, like when piped into `head`
+			if write!(stdout, "{}", c)
+				.and_then(|_| stdout.flush())
+				.is_err()
+			{
+				return Ok(Control::Exit(1));
+			}
+			Ok(Control::Continue)
+		});
+		syscalls.builtin(syscall::GETCHAR, |stack, _| {
+			// -1 at the end of input
+			stack.push(read_char().map_or(isa::WORD_MASK, |c| c as u32));
+			Ok(Control::Continue)
+		});
+		syscalls.builtin(syscall::EXIT, |stack, _| Ok(Control::Exit(stack.pop()?)));
+		let mut state = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |t| t.subsec_nanos())
+			| 1;
+		syscalls.builtin(syscall::RANDOM, move |stack, _| {
+			// xorshift32
+			state ^= state << 13;
+			state ^= state >> 17;
+			state ^= state << 5;
+			stack.push(state);
+			Ok(Control::Continue)
+		});
+		let start = Instant::now();
+		syscalls.builtin(syscall::CLOCK, move |stack, _| {
+			stack.push(start.elapsed().as_millis() as u32);
+			Ok(Control::Continue)
+		});
+		syscalls
+	}
+	fn builtin(
+		self: &mut Self,
+		number: u32,
+		handler: impl FnMut(&mut StackAccess, &mut MemoryAccessor) -> Result<Control, VmFault> + 'static,
+	) {
+		let (args, ret) = syscall::signature(number).unwrap();
+		self.register(number, args, ret, Box::new(handler));
+	}
+	/// Adds a system call, replacing any existing one with the same number.
+	pub fn register(self: &mut Self, number: u32, args: u32, ret: u32, handler: Handler) {
+		self.calls.insert(number, Syscall { args, ret, handler });
+	}
+	pub fn signature(self: &Self, number: u32) -> Result<u32, VmFault> {
+		self.calls
+			.get(&number)
+			.map(|s| isa::signature(s.args, s.ret))
+			.ok_or(VmFault::UnknownSyscall(number))
+	}
+	pub fn call(
+		self: &mut Self,
+		number: u32,
+		stack: &mut StackAccess,
+		memory: &mut MemoryAccessor,
+	) -> Result<Control, VmFault> {
+		match self.calls.get_mut(&number) {
+			Some(s) => (s.handler)(stack, memory),
+			None => Err(VmFault::UnknownSyscall(number)),
+		}
+	}
+}
+
+/// Reads a single UTF-8 encoded character from stdin.
+fn read_char() -> Option<char> {
+	let stdin = io::stdin();
+	let mut stdin = stdin.lock();
+	let mut buf = [0; 4];
+	for len in 1..=buf.len() {
+		stdin.read_exact(&mut buf[len - 1..len]).ok()?;
+		if let Ok(s) = std::str::from_utf8(&buf[..len]) {
+			return s.chars().next();
+		}
+	}
+	Some(std::char::REPLACEMENT_CHARACTER)
+}